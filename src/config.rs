@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::ops::Not;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 use serde::{Serialize, Deserialize};
@@ -17,6 +18,11 @@ use add_ed::macros::Macro;
 // Import default config
 const DEFAULT_CONFIG: &str = include_str!("../default_config.yaml");
 
+// Project configuration is looked for from the opened file's directory (or the
+// working directory) upwards, in a file and/or directory with these names
+const PROJECT_CONFIG_NAME: &str = ".hired.yaml";
+pub const PROJECT_DIR_NAME: &str = ".hired";
+
 // The CLI arguments struct
 // We do some fancy serde attrs to not serialize any arg not given
 /// hired, the highlighting EDitor
@@ -39,6 +45,10 @@ struct Args {
   #[clap(action, short)]
   #[serde(skip_serializing_if = "<&bool>::not")]
   l: bool,
+  /// color theme to use, name of an embedded theme or path to a .tmTheme file
+  #[clap(long)]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  theme: Option<String>,
   /// open configuration file
   #[clap(action, long)]
   #[arg(conflicts_with("path"))]
@@ -54,6 +64,16 @@ struct Args {
   #[arg(conflicts_with_all(["path", "open_config", "create_config"]))]
   #[serde(skip_serializing)]
   attributions: bool,
+  /// print all macros in the selected profile, with their definitions
+  #[clap(action, long)]
+  #[arg(conflicts_with_all(["path", "open_config", "create_config", "attributions"]))]
+  #[serde(skip_serializing)]
+  list_macros: bool,
+  /// check all profiles in the configuration files and all macro files
+  #[clap(action, long)]
+  #[arg(conflicts_with_all(["path", "open_config", "create_config", "attributions", "list_macros"]))]
+  #[serde(skip_serializing)]
+  check_config: bool,
 }
 
 // The configuration struct
@@ -69,19 +89,82 @@ pub struct Config {
   pub n: bool,
   #[serde(default)]
   pub l: bool,
+  // Color theme, name of an embedded theme or path to a .tmTheme file
+  // (relative paths are relative to the config directory, or to the working
+  // directory if given on the CLI or in the environment)
+  #[serde(default)]
+  pub theme: Option<String>,
+  // Syntax to use for files matching a glob, by syntax name or extension
+  #[serde(default)]
+  pub syntax_overrides: HashMap<String, String>,
+  // If long lines should be wrapped, else they are cut off at terminal width
+  #[serde(default = "default_true")]
+  pub wrap: bool,
+  // Width of tab stops when printing
+  #[serde(default = "default_tab_width")]
+  pub tab_width: usize,
+  // Tab width for specific syntaxes, by syntax name or extension
+  #[serde(default)]
+  pub syntax_tab_widths: HashMap<String, usize>,
   // Defined macros
   #[serde(default)]
   pub macros: HashMap<String, Macro>,
+  // If substitutions and global commands should be previewed while typed
+  #[serde(default)]
+  pub preview: bool,
+  // How to mark regex matches, such as those of the last search when printing
+  #[serde(default)]
+  pub match_style: MatchStyle,
+  // Max number of commands to remember in the command history
+  #[serde(default = "default_history_size")]
+  pub history_size: usize,
+  // If the command history should be saved between sessions
+  #[serde(default = "default_true")]
+  pub persist_history: bool,
+  // Where to save the command history, not configurable
+  // (set by construct_config to a file in the platform specific data dir)
+  #[serde(skip)]
+  pub history_path: Option<PathBuf>,
+  // The directory holding the config file (and user syntaxes), not configurable
+  #[serde(skip)]
+  pub config_dir: PathBuf,
+  // The directory holding trusted project configuration, if any was found
+  #[serde(skip)]
+  pub project_dir: Option<PathBuf>,
+  // Platform specific directory for caches and such, not configurable
+  #[serde(skip)]
+  pub data_dir: PathBuf,
+  // If we should list the macros and exit, given by CLI argument
+  // (handled by main, since macros are also read from macro files)
+  #[serde(skip)]
+  pub list_macros: bool,
 }
+// Styles for marking matches, applied on top of the syntax highlighting
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStyle {
+  #[default]
+  Reverse,
+  Underline,
+  Bold,
+}
+
+fn default_tab_width() -> usize { 4 }
+fn default_history_size() -> usize { 1000 }
+fn default_true() -> bool { true }
 
 pub fn construct_config() -> Config {
-  // First figure out platform specific config paths
-  let (config_dir, config_path) = {
+  // First figure out platform specific config and data paths
+  let (config_dir, config_path, data_dir) = {
     let app_dirs = directories::ProjectDirs::from("se", "sidju", "hired")
       .expect("Failed to find a config directory. Is $HOME configured?")
     ;
     // Return the needed data from this block
-    (app_dirs.config_dir().to_owned(), app_dirs.config_dir().join("config.yaml"))
+    (
+      app_dirs.config_dir().to_owned(),
+      app_dirs.config_dir().join("config.yaml"),
+      app_dirs.data_dir().to_owned(),
+    )
   };
   // Parse arguments first, so we can see if we should create a default config
   let args = Args::parse();
@@ -109,7 +192,7 @@ pub fn construct_config() -> Config {
     else {
       // We need to first create our project folder in the config folder
       if !config_dir.is_dir() {
-        std::fs::DirBuilder::new().create(config_dir)
+        std::fs::DirBuilder::new().create(&config_dir)
           .expect("Error when creating config directory for hired.")
         ;
       }
@@ -118,25 +201,177 @@ pub fn construct_config() -> Config {
       ;
     }
   }
-  let mut config: Config = Figment::new()
+  // Look for project configuration, which is only used if trusted since it
+  // can define macros running shell commands
+  let project_dir = {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let start = args.path.as_ref()
+      .and_then(|path| std::path::Path::new(path).parent())
+      .map(|dir| cwd.join(dir))
+      .unwrap_or(cwd)
+    ;
+    find_project_dir(&start)
+      .filter(|dir| trust_project(dir, &data_dir.join("trusted_projects")))
+  };
+  // If requested we check the configuration files and exit
+  if args.check_config {
+    let mut files = vec![config_path.clone()];
+    if let Some(dir) = &project_dir {
+      files.push(dir.join(PROJECT_CONFIG_NAME));
+    }
+    let macro_dirs = macro_dirs(&config_dir, project_dir.as_deref());
+    if crate::config_check::check_files(&files, &macro_dirs, &config_dir, &data_dir) {
+      println!("No problems found.");
+      std::process::exit(0);
+    }
+    std::process::exit(1);
+  }
+  let mut figment = Figment::new()
     // Read in config file
     .merge(YamlExtended::file(&config_path).nested())
+  ;
+  if let Some(dir) = &project_dir {
+    // Project configuration overrides user configuration (if it has the file)
+    figment = figment.merge(YamlExtended::file(dir.join(PROJECT_CONFIG_NAME)).nested());
+  }
+  let figment = figment
     // Read in overrides from environment
     .merge(Env::prefixed("HIRED_").global())
     // Allow CLI arguments to override configuration
     .merge(Serialized::globals(&args))
     // Select which profile to load config from
     .select(&args.profile)
-    // Convert back into config struct and verify it is valid
-    .extract()
-    .expect("Invalid configuration")
   ;
+  // Convert back into config struct and verify it is valid
+  let extracted = figment.extract::<Config>();
+  let mut config = match extracted {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("Invalid configuration:");
+      for problem in crate::config_check::describe_errors(e) {
+        eprintln!("  {}", problem);
+      }
+      eprintln!("(Run with --check-config to check all profiles.)");
+      std::process::exit(1);
+    },
+  };
   // If open/create config is given we overwrite any given path with config path
   if args.open_config || args.create_config {
     config.path = config_path.into_os_string().into_string()
       .expect("Config path isn't valid unicode.")
     ;
   }
+  // Theme paths from configuration files are relative to the config directory
+  // (as load_theme takes them), but from the CLI or environment they are
+  // relative to the working directory
+  let theme_from_file = matches!(
+    figment.find_metadata("theme").and_then(|m| m.source.as_ref()),
+    Some(figment::Source::File(_)),
+  );
+  if let Some(theme) = config.theme.as_mut() {
+    if !theme_from_file && crate::hui::is_theme_path(theme) {
+      let cwd = std::env::current_dir().unwrap_or_default();
+      *theme = cwd.join(&*theme).to_string_lossy().into_owned();
+    }
+  }
+  config.config_dir = config_dir;
+  config.project_dir = project_dir;
+  if config.persist_history {
+    config.history_path = Some(data_dir.join("command_history"));
+  }
+  config.data_dir = data_dir;
+  config.list_macros = args.list_macros;
 
   config
 }
+
+// The directories to read macro files from, in order of priority
+pub fn macro_dirs(config_dir: &Path, project_dir: Option<&Path>) -> Vec<PathBuf> {
+  let mut dirs = Vec::new();
+  if let Some(dir) = project_dir {
+    dirs.push(dir.join(PROJECT_DIR_NAME).join("macros"));
+  }
+  dirs.push(config_dir.join("macros"));
+  dirs
+}
+
+// Find the nearest directory from start upwards with project configuration
+fn find_project_dir(start: &Path) -> Option<PathBuf> {
+  start.ancestors()
+    .find(|dir|
+      dir.join(PROJECT_CONFIG_NAME).is_file() || dir.join(PROJECT_DIR_NAME).is_dir()
+    )
+    .map(|dir| dir.to_owned())
+}
+
+// SHA-256 of everything we load from the project directory, in hex
+// Each file is hashed with its name and the lengths of both, so that content
+// can't be moved between files without changing the digest.
+fn project_digest(project_dir: &Path) -> String {
+  use sha2::{Digest, Sha256};
+  let mut hasher = Sha256::new();
+  let mut hash_file = |name: &[u8], path: &Path| {
+    // Missing or unreadable files are hashed as empty
+    let contents = std::fs::read(path).unwrap_or_default();
+    hasher.update((name.len() as u64).to_le_bytes());
+    hasher.update(name);
+    hasher.update((contents.len() as u64).to_le_bytes());
+    hasher.update(&contents);
+  };
+  hash_file(PROJECT_CONFIG_NAME.as_bytes(), &project_dir.join(PROJECT_CONFIG_NAME));
+  let macro_dir = project_dir.join(PROJECT_DIR_NAME).join("macros");
+  let mut macro_files: Vec<PathBuf> = std::fs::read_dir(&macro_dir)
+    .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+    .unwrap_or_default()
+  ;
+  macro_files.sort();
+  for path in macro_files {
+    let name = path.file_name().unwrap_or_default().as_encoded_bytes().to_vec();
+    hash_file(&name, &path);
+  }
+  hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Checks if the project directory is trusted, asking the user if not known
+// Trusted directories are saved in the trust file with a digest of their
+// config file and macro files, so changes to them have to be trusted anew.
+fn trust_project(project_dir: &Path, trust_path: &Path) -> bool {
+  use std::io::{IsTerminal, Write};
+  let entry = format!("{} {}", project_digest(project_dir), project_dir.display());
+  let trusted = std::fs::read_to_string(trust_path).unwrap_or_default();
+  if trusted.lines().any(|line| line == entry) {
+    return true;
+  }
+  // If we can't ask we don't trust it
+  if !std::io::stdin().is_terminal() {
+    eprintln!(
+      "Ignoring untrusted project configuration in {}.",
+      project_dir.display(),
+    );
+    return false;
+  }
+  println!("Found new or changed project configuration in {}.", project_dir.display());
+  println!("It can define macros that run shell commands, so only trust it if you trust its source.");
+  print!("Trust it? [y]es, [o]nce, [N]o: ");
+  std::io::stdout().flush().ok();
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer).ok();
+  match answer.trim() {
+    "y" | "Y" | "yes" => {
+      // Failing to save only means we ask again next time
+      if let Some(dir) = trust_path.parent() {
+        std::fs::create_dir_all(dir).ok();
+      }
+      std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(trust_path)
+        .and_then(|mut file| writeln!(file, "{}", entry))
+        .ok()
+      ;
+      true
+    },
+    "o" | "O" | "once" => true,
+    _ => false,
+  }
+}
@@ -0,0 +1,271 @@
+// Readable reports of configuration problems, pointing out where they are
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use figment::{
+  Figment,
+  Source,
+  error::Kind,
+  providers::{
+    YamlExtended,
+    Format,
+  },
+};
+
+use crate::config::Config;
+
+/// Describe each error from extracting the configuration
+///
+/// Errors are given with the file, line and key path they were found at, as
+/// far as that is known.
+pub fn describe_errors(error: figment::Error) -> Vec<String> {
+  let mut problems = Vec::new();
+  for e in error {
+    let problem = describe_error(&e);
+    // Errors in shared profiles are found once per selected profile
+    if !problems.contains(&problem) { problems.push(problem); }
+  }
+  problems
+}
+
+fn describe_error(e: &figment::Error) -> String {
+  let mut path = e.path.clone();
+  // Unknown fields are reported on the struct they were found in
+  if let Kind::UnknownField(field, _) = &e.kind {
+    if path.last() != Some(field) { path.push(field.clone()); }
+  }
+  let profile = e.profile.as_ref().map(|p| p.as_str().as_str());
+  let file = e.metadata.as_ref()
+    .and_then(|m| m.source.as_ref())
+    .and_then(|source| match source {
+      Source::File(file) => Some(file.clone()),
+      _ => None,
+    })
+  ;
+  match file {
+    Some(file) => {
+      let contents = std::fs::read_to_string(&file).unwrap_or_default();
+      let (profile, line) = locate(&contents, profile, &path);
+      describe_at(&file, line, &key_path(profile.as_deref(), &path), &e.kind)
+    },
+    // Environment and CLI arguments have no lines to point at
+    None => format!(
+      "{}: {}: {}",
+      e.metadata.as_ref().map(|m| m.name.to_string()).unwrap_or("configuration".to_string()),
+      key_path(profile, &path),
+      e.kind,
+    ),
+  }
+}
+
+fn describe_at(
+  file: &Path,
+  line: Option<usize>,
+  key_path: &str,
+  problem: &dyn std::fmt::Display,
+) -> String {
+  match line {
+    Some(line) => format!("{}:{}: {}: {}", file.display(), line, key_path, problem),
+    None => format!("{}: {}: {}", file.display(), key_path, problem),
+  }
+}
+
+fn key_path(profile: Option<&str>, path: &[String]) -> String {
+  profile.into_iter()
+    .chain(path.iter().map(|key| key.as_str()))
+    .collect::<Vec<_>>()
+    .join(".")
+}
+
+/// Check every profile in the given configuration files, and all macro files
+///
+/// Prints each problem found to stderr, returns true if there were none.
+/// Files that don't exist are skipped.
+pub fn check_files(
+  files: &[PathBuf],
+  macro_dirs: &[PathBuf],
+  config_dir: &Path,
+  data_dir: &Path,
+) -> bool {
+  let mut problems = Vec::new();
+  for file in files {
+    let contents = match std::fs::read_to_string(file) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+      Err(e) => {
+        problems.push(format!("{}: {}", file.display(), e));
+        continue;
+      },
+    };
+    // The profiles are the top level keys
+    let profiles: Vec<String> = match serde_yaml::from_str::<serde_yaml::Value>(&contents) {
+      Ok(serde_yaml::Value::Mapping(profiles)) => profiles.keys()
+        .filter_map(|key| key.as_str().map(|key| key.to_owned()))
+        .collect(),
+      Ok(serde_yaml::Value::Null) => Vec::new(),
+      Ok(_) => {
+        problems.push(format!("{}: expected a mapping of profiles", file.display()));
+        continue;
+      },
+      Err(e) => {
+        problems.push(format!("{}: {}", file.display(), e));
+        continue;
+      },
+    };
+    for profile in profiles {
+      let found = match Figment::from(YamlExtended::file(file).nested())
+        .select(&profile)
+        .extract::<Config>()
+      {
+        Ok(mut config) => {
+          // Set what the UI needs to find themes and syntaxes
+          config.config_dir = config_dir.to_owned();
+          config.data_dir = data_dir.to_owned();
+          crate::hui::check_config(&config).into_iter()
+            .map(|(path, e)| {
+              let (profile, line) = locate(&contents, Some(profile.as_str()), &path);
+              describe_at(file, line, &key_path(profile.as_deref(), &path), &e)
+            })
+            .collect()
+        },
+        Err(e) => describe_errors(e),
+      };
+      for problem in found {
+        if !problems.contains(&problem) { problems.push(problem); }
+      }
+    }
+  }
+  // Macro files are otherwise only checked when used
+  let no_macros = HashMap::new();
+  let macro_store = crate::macro_store::MacroStore::new(&no_macros, macro_dirs);
+  for e in macro_store.check_files() {
+    problems.push(e.to_string());
+  }
+  // As are macro files the UI commands would hide
+  for name in macro_store.names() {
+    if !crate::hui::UI_COMMANDS.contains(&name) { continue; }
+    if let Some(path) = macro_store.file_path(name) {
+      let e = crate::hui::error::HighlightingUIError::MacroHidden(name.to_owned());
+      problems.push(format!("{}: {}", path.display(), e));
+    }
+  }
+
+  for problem in &problems {
+    eprintln!("{}", problem);
+  }
+  problems.is_empty()
+}
+
+// Find the profile and line of a key path in a configuration file
+// Values in a profile may come from the default and global profiles, so those
+// are looked in if the key path isn't fully found in the given profile.
+fn locate(
+  contents: &str,
+  profile: Option<&str>,
+  path: &[String],
+) -> (Option<String>, Option<usize>) {
+  let profile = match profile {
+    Some(profile) => profile,
+    None => return (None, find_key_line(contents, path).0),
+  };
+  let keys = |profile: &str| -> Vec<String> {
+    std::iter::once(profile.to_owned()).chain(path.iter().cloned()).collect()
+  };
+  let (line, depth) = find_key_line(contents, &keys(profile));
+  if depth <= path.len() {
+    for shared in ["global", "default"] {
+      if shared == profile { continue; }
+      let (shared_line, shared_depth) = find_key_line(contents, &keys(shared));
+      if shared_depth == path.len() + 1 {
+        return (Some(shared.to_owned()), shared_line);
+      }
+    }
+  }
+  (Some(profile.to_owned()), line)
+}
+
+// Find the line (1-indexed) of the deepest key in the key path that can be
+// found by following the indentation of the YAML, and how many keys deep it is
+// (Keys brought in by anchors or flow style mappings aren't found, then we
+// point at their parent.)
+fn find_key_line(contents: &str, keys: &[String]) -> (Option<usize>, usize) {
+  let lines: Vec<&str> = contents.lines().collect();
+  let mut found = None;
+  let mut depth = 0;
+  let mut start = 0;
+  let mut parent_indent: Option<usize> = None;
+  'keys: for key in keys {
+    // The indentation of the first key in the block is that of all its keys
+    let mut block_indent = None;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+      let trimmed = line.trim_start();
+      if trimmed.is_empty() || trimmed.starts_with('#') { continue; }
+      let indent = line.len() - trimmed.len();
+      if parent_indent.map(|parent| indent <= parent).unwrap_or(false) { break; }
+      let block_indent = *block_indent.get_or_insert(indent);
+      if indent != block_indent { continue; }
+      let line_key = trimmed.split_once(':')
+        .map(|(line_key, _)| line_key.trim().trim_matches(|c| c == '"' || c == '\''))
+      ;
+      if line_key == Some(key.as_str()) {
+        found = Some(i + 1);
+        depth += 1;
+        start = i + 1;
+        parent_indent = Some(indent);
+        continue 'keys;
+      }
+    }
+    break;
+  }
+  (found, depth)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const CONFIG: &str = "\
+default:
+
+example:
+  # A comment: with a colon
+  theme: base16
+  macros:
+    'grep':
+      input: g/$1/p
+      nr_arguments: 1
+    copy:
+      <<: *foo
+";
+  fn keys(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|key| key.to_string()).collect()
+  }
+
+  #[test]
+  fn key_lines() {
+    assert_eq!(find_key_line(CONFIG, &keys(&["example", "theme"])), (Some(5), 2));
+    assert_eq!(
+      find_key_line(CONFIG, &keys(&["example", "macros", "grep", "nr_arguments"])),
+      (Some(9), 4),
+    );
+    // Nested keys aren't mistaken for keys of their parent
+    assert_eq!(find_key_line(CONFIG, &keys(&["example", "input"])), (Some(3), 1));
+    // Keys from anchors point at the parent
+    assert_eq!(
+      find_key_line(CONFIG, &keys(&["example", "macros", "copy", "input"])),
+      (Some(10), 3),
+    );
+    assert_eq!(find_key_line(CONFIG, &keys(&["other"])), (None, 0));
+  }
+  #[test]
+  fn shared_profiles() {
+    let config = "default:\n  tab_width: x\nexample:\n  n: true\n";
+    assert_eq!(
+      locate(config, Some("example"), &keys(&["tab_width"])),
+      (Some("default".to_string()), Some(2)),
+    );
+    assert_eq!(
+      locate(config, Some("example"), &keys(&["n"])),
+      (Some("example".to_string()), Some(4)),
+    );
+  }
+}
@@ -0,0 +1,285 @@
+// Tab completion for command input
+use unicode_width::UnicodeWidthStr;
+
+pub struct Candidate {
+  // Text to replace the completed word with
+  pub replacement: String,
+  // How to show the candidate when listing candidates
+  pub display: String,
+}
+
+// The possible completions of the word before the cursor
+pub struct Completions {
+  // Byte index in the command where the completed word starts
+  pub start: usize,
+  pub candidates: Vec<Candidate>,
+}
+
+// The commands of add-ed with a short summary, for listing on Tab
+// Must list exactly the commands add-ed knows, which a test checks.
+const COMMANDS: &[(&str, &str)] = &[
+  ("a", "append input after selection"),
+  ("i", "insert input before selection"),
+  ("c", "change selection into input"),
+  ("A", "append input, joining first line onto selection"),
+  ("I", "insert input, joining last line onto selection"),
+  ("C", "change selection, with selection as initial input"),
+  ("d", "cut selection into clipboard"),
+  ("y", "yank (copy) selection into clipboard"),
+  ("x", "paste clipboard after selection"),
+  ("X", "paste clipboard before selection"),
+  ("m", "move selection to after given line"),
+  ("t", "transfer (copy) selection to after given line"),
+  ("j", "join selected lines"),
+  ("k", "tag first line of selection with given char"),
+  ("K", "tag last line of selection with given char"),
+  ("s", "substitute regex matches in selection"),
+  ("g", "run commands on lines matching regex"),
+  ("v", "run commands on lines not matching regex"),
+  ("G", "interactively run commands on lines matching regex"),
+  ("V", "interactively run commands on lines not matching regex"),
+  ("p", "print selection"),
+  ("n", "print selection with line numbers"),
+  ("l", "print selection literally"),
+  ("z", "print given nr of lines after selection"),
+  ("Z", "print given nr of lines before selection"),
+  ("=", "print selection line numbers"),
+  ("#", "comment, or set selection without printing"),
+  ("P", "toggle printing with line numbers (n) or literally (l) by default"),
+  ("e", "edit given file"),
+  ("E", "edit given file, discarding unsaved changes"),
+  ("r", "read given file into buffer after selection"),
+  ("w", "write selection to given file"),
+  ("W", "append selection to given file"),
+  ("f", "show or set default file"),
+  ("!", "run shell command"),
+  ("|", "filter selection through shell command"),
+  ("u", "undo given nr of steps, or redo if negative"),
+  ("U", "list undo history"),
+  ("h", "print last error"),
+  ("H", "toggle printing errors or only ?"),
+  ("help", "list commands"),
+  ("Help", "print documentation of commands"),
+  ("q", "quit"),
+  ("Q", "quit, discarding unsaved changes"),
+  (":", "run macro"),
+];
+
+// Get completions for the given command, which should end at the cursor
+// Macros are given as pairs of name and description
+// Returns None if there is nothing to complete at that position
+pub fn complete_command(
+  command: &str,
+  macros: &[(String, String)],
+) -> Option<Completions> {
+  // If there is no command yet we list the commands
+  let (index, ch) = match find_command(command) {
+    Some(x) => x,
+    None => return Some(Completions{
+      start: command.len(),
+      candidates: COMMANDS.iter()
+        .map(|(command, summary)| Candidate{
+          replacement: command.to_string(),
+          display: format!("{}  {}", command, summary),
+        })
+        .collect()
+      ,
+    }),
+  };
+  let arg_start = index + ch.len_utf8();
+  match ch {
+    // Macro invocations complete the macro name
+    ':' => complete_macro(command, arg_start, macros),
+    // Commands taking a path, or '!' and a shell command
+    'e' | 'E' | 'r' | 'w' | 'W' => {
+      let arg = &command[arg_start ..];
+      let arg_start = arg_start + (arg.len() - arg.trim_start().len());
+      if command[arg_start ..].starts_with('!') {
+        complete_shell(command, arg_start + 1)
+      }
+      else {
+        complete_path(command, arg_start)
+      }
+    },
+    // Commands taking a shell command
+    '!' | '|' => complete_shell(command, arg_start),
+    _ => None,
+  }
+}
+
+// Finds the command char and its index, skipping the selection before it
+pub fn find_command(command: &str) -> Option<(usize, char)> {
+  let mut iter = command.char_indices();
+  while let Some((i, ch)) = iter.next() {
+    match ch {
+      '0' ..= '9' | ',' | ';' | '.' | '$' | '+' | '-' | ' ' => (),
+      // Marks are a quote followed by the mark
+      '\'' => { iter.next(); },
+      // Regex selections, skip past the closing delimiter
+      '/' | '?' => {
+        let mut escaped = false;
+        for (_, c) in iter.by_ref() {
+          if escaped { escaped = false; }
+          else if c == '\\' { escaped = true; }
+          else if c == ch { break; }
+        }
+      },
+      _ => return Some((i, ch)),
+    }
+  }
+  None
+}
+
+// Completes the macro name, if the cursor is still within it
+fn complete_macro(
+  command: &str,
+  start: usize,
+  macros: &[(String, String)],
+) -> Option<Completions> {
+  let name = &command[start ..];
+  if name.contains(char::is_whitespace) { return None; }
+  Some(Completions{
+    start: start,
+    candidates: macros.iter()
+      .filter(|(macro_name, _)| macro_name.starts_with(name))
+      .map(|(macro_name, description)| Candidate{
+        replacement: macro_name.clone(),
+        display: format!("{}  ({})", macro_name, description),
+      })
+      .collect()
+    ,
+  })
+}
+
+// Completes the last word of a shell command as a path
+fn complete_shell(command: &str, start: usize) -> Option<Completions> {
+  let word_start = command[start ..].rfind(char::is_whitespace)
+    .map(|i| start + i + 1)
+    .unwrap_or(start)
+  ;
+  complete_path(command, word_start)
+}
+
+// Completes the path from start to the end of the command, relative to cwd
+fn complete_path(command: &str, start: usize) -> Option<Completions> {
+  let word = &command[start ..];
+  let (dir, prefix) = match word.rfind('/') {
+    Some(i) => (&word[.. i + 1], &word[i + 1 ..]),
+    None => ("", word),
+  };
+  let read_from = if dir.is_empty() { "." } else { dir };
+  let mut candidates: Vec<Candidate> = std::fs::read_dir(read_from).ok()?
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| {
+      let name = entry.file_name().into_string().ok()?;
+      // Only show hidden files if asked for
+      if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+        return None;
+      }
+      // Directories get a trailing '/', so completion can continue into them
+      let display = if entry.path().is_dir() { format!("{}/", name) } else { name };
+      Some(Candidate{
+        replacement: format!("{}{}", dir, display),
+        display: display,
+      })
+    })
+    .collect()
+  ;
+  candidates.sort_by(|a, b| a.display.cmp(&b.display));
+  Some(Completions{
+    start: start,
+    candidates: candidates,
+  })
+}
+
+// The longest prefix shared by all the candidates' replacements
+pub fn common_prefix(candidates: &[Candidate]) -> &str {
+  let first = match candidates.first() {
+    Some(c) => &c.replacement,
+    None => return "",
+  };
+  let mut len = first.len();
+  for c in &candidates[1 ..] {
+    len = len.min(
+      first.bytes().zip(c.replacement.bytes()).take_while(|(a, b)| a == b).count()
+    );
+  }
+  // Don't cut a char in half
+  while !first.is_char_boundary(len) { len -= 1; }
+  &first[.. len]
+}
+
+// Lay out the candidates in columns fitting the given width
+// If there are too many the rest are summarized in a last line
+pub fn format_candidates(candidates: &[Candidate], width: usize) -> Vec<String> {
+  const MAX_ROWS: usize = 10;
+  let col_width = candidates.iter()
+    .map(|c| c.display.width())
+    .max()
+    .unwrap_or(0) + 2
+  ;
+  let per_row = (width / col_width).max(1);
+  let mut rows: Vec<String> = candidates.chunks(per_row)
+    .map(|row| row.iter()
+      // Pad by display width, since format! pads by nr of chars
+      .map(|c| format!(
+        "{}{}",
+        c.display,
+        " ".repeat(col_width - c.display.width()),
+      ))
+      .collect::<String>()
+      .trim_end()
+      .to_string()
+    )
+    .collect()
+  ;
+  if rows.len() > MAX_ROWS {
+    rows.truncate(MAX_ROWS);
+    rows.push(format!("...and {} more", candidates.len() - MAX_ROWS * per_row));
+  }
+  rows
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn find_command_after_selection() {
+    assert_eq!(find_command("w"), Some((0, 'w')));
+    assert_eq!(find_command("1,$w file"), Some((3, 'w')));
+    assert_eq!(find_command("'a,'br"), Some((5, 'r')));
+    assert_eq!(find_command("/fo\\/o/,/bar/!"), Some((13, '!')));
+    assert_eq!(find_command("1,5"), None);
+  }
+  #[test]
+  fn common_prefix_of_candidates() {
+    let candidates: Vec<Candidate> = ["src/main.rs", "src/macro_store.rs"].iter()
+      .map(|s| Candidate{ replacement: s.to_string(), display: s.to_string() })
+      .collect()
+    ;
+    assert_eq!(common_prefix(&candidates), "src/ma");
+    assert_eq!(common_prefix(&[]), "");
+  }
+  #[test]
+  fn commands_match_add_ed() {
+    use add_ed::{Ed, EdError, io::fake_io::FakeIO, ui::dummy_ui::DummyUI};
+    let mut io = FakeIO{
+      fake_fs: std::collections::HashMap::new(),
+      fake_shell: std::collections::HashMap::new(),
+    };
+    let macros: std::collections::HashMap<&str, add_ed::macros::Macro> =
+      std::collections::HashMap::new();
+    let mut ed = Ed::new(&mut io, &macros);
+    let mut ui = DummyUI{};
+    // Every char that would be taken as a command is either listed or unknown
+    for ch in (' ' ..= '~').filter(|ch| find_command(&ch.to_string()).is_some()) {
+      let known = !matches!(
+        ed.run_command(&mut ui, &format!("{}\n", ch)),
+        Err(EdError::CommandUndefined(_)),
+      );
+      let listed = COMMANDS.iter().any(|(command, _)| *command == ch.to_string());
+      assert_eq!(known, listed, "command '{}'", ch);
+    }
+  }
+}
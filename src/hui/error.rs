@@ -7,8 +7,22 @@ pub enum HighlightingUIError {
   TerminalIOFailed(std::io::Error),
   // Received Ctrl+c, aborting input and returning to editor.
   Interrupted,
+  // Received Esc, aborting command input. Handled by prompting again.
+  Cancelled,
   // Terminal not wide enough to print docs
   DocInsufficientWidth(termimad::InsufficientWidthError),
+  // Configured theme isn't the name of any embedded theme
+  UnknownTheme(String),
+  // Configured theme file couldn't be loaded
+  ThemeLoadFailed(String, two_face::re_exports::syntect::LoadingError),
+  // Configured syntax override or tab width names no known syntax
+  UnknownSyntax(String),
+  // User syntax definitions couldn't be loaded
+  SyntaxLoadFailed(String, two_face::re_exports::syntect::LoadingError),
+  // A macro has the name of a UI command, which would hide it
+  MacroHidden(String),
+  // Syntax highlighting failed, which should only happen with broken syntaxes
+  HighlightingFailed(two_face::re_exports::syntect::parsing::ParsingError),
 }
 impl std::fmt::Display for HighlightingUIError {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -36,6 +50,9 @@ impl std::fmt::Display for HighlightingUIError {
       HE::Interrupted => {
         write!(f, "Interrupted!")
       },
+      HE::Cancelled => {
+        write!(f, "Cancelled.")
+      },
       HE::DocInsufficientWidth(e) => {
         write!(f,
           concat!(
@@ -45,6 +62,52 @@ impl std::fmt::Display for HighlightingUIError {
           e
         )
       },
+      HE::UnknownTheme(name) => {
+        writeln!(f, "Unknown theme '{}'. Valid theme names are:", name)?;
+        for theme in two_face::theme::EmbeddedLazyThemeSet::theme_names() {
+          writeln!(f, "  {}", theme.as_name())?;
+        }
+        write!(f, "Alternatively give a path to a .tmTheme file.")
+      },
+      HE::ThemeLoadFailed(path, e) => {
+        write!(f,
+          concat!(
+            "Failed to load theme from '{}'.\n\n",
+            "Underlying error: {}",
+          ),
+          path,
+          e
+        )
+      },
+      HE::UnknownSyntax(name) => {
+        write!(f, "Unknown syntax '{}' in syntax_overrides or syntax_tab_widths.", name)
+      },
+      HE::SyntaxLoadFailed(path, e) => {
+        write!(f,
+          concat!(
+            "Failed to load syntax definitions from '{}'.\n\n",
+            "Underlying error: {}",
+          ),
+          path,
+          e
+        )
+      },
+      HE::MacroHidden(name) => {
+        write!(f,
+          "Macro '{}' can't be run, since ':{}' is a UI command. Rename the macro.",
+          name,
+          name,
+        )
+      },
+      HE::HighlightingFailed(e) => {
+        write!(f,
+          concat!(
+            "Failed to highlight text.\n\n",
+            "Underlying error: {}",
+          ),
+          e
+        )
+      },
     }
   }
 }
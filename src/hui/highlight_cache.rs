@@ -0,0 +1,169 @@
+// Caches syntax highlighting state at regular intervals through the buffer
+// Used so printing deep into a big buffer doesn't require highlighting every
+// preceding line from scratch on every print.
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use two_face::re_exports::syntect::parsing::{
+  ParseState,
+  ParsingError,
+  ScopeStack,
+  SyntaxReference,
+  SyntaxSet,
+};
+use two_face::re_exports::syntect::highlighting::{
+  HighlightIterator,
+  HighlightState,
+  Highlighter,
+  Theme,
+};
+
+// Nr of lines between each saved state
+const CHECKPOINT_INTERVAL: usize = 256;
+
+// Identifies a buffer state, so we only compare lines when it changes
+// (Built by the UI from the editor history, see HighlightingUI::snapshot_key)
+pub type SnapshotKey = (usize, usize);
+
+#[derive(Default)]
+pub struct HighlightCache {
+  // Name of the syntax the states were created with
+  syntax: String,
+  // The buffer state the line hashes are known to match, if any
+  snapshot: Option<SnapshotKey>,
+  // Hash of each line we have highlighted, to find where the buffer changed
+  line_hashes: Vec<u64>,
+  // The states before every CHECKPOINT_INTERVAL:th line, from line 0
+  checkpoints: Vec<(HighlightState, ParseState)>,
+}
+impl HighlightCache {
+  // Get the highlighting state after the first nr_lines lines of the buffer
+  // The lines must be those lines, from buffer start.
+  // If the snapshot key differs from the last call all lines are compared with
+  // what we have seen before, discarding the checkpoints after the first
+  // changed line. Otherwise the lines before the last usable checkpoint are
+  // assumed unchanged, and only the lines after it are read.
+  // A None snapshot means the buffer is changing in place, then we can't know
+  // what changed without comparing every line on every call. So we only check
+  // the lines we read, and compare all lines once we get a snapshot again.
+  pub fn state_after<'a>(
+    &mut self,
+    syntax_lib: &SyntaxSet,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    snapshot: Option<SnapshotKey>,
+    nr_lines: usize,
+    mut lines: impl Iterator<Item = &'a str>,
+  ) -> Result<(HighlightState, ParseState), ParsingError> {
+    // A different syntax invalidates everything
+    if self.syntax != syntax.name {
+      self.syntax = syntax.name.clone();
+      self.snapshot = None;
+      self.line_hashes.clear();
+      self.checkpoints.clear();
+    }
+    // If we got a new snapshot we read all the lines to compare them
+    // (They are then highlighted from here, since the iterator is consumed.)
+    let mut compared = Vec::new();
+    if snapshot.is_some() && snapshot != self.snapshot {
+      compared.extend(lines.by_ref());
+      self.invalidate_changed(&compared);
+    }
+    self.snapshot = snapshot;
+
+    // Start from the last checkpoint before the wanted state, if any
+    let highlighter = Highlighter::new(theme);
+    let usable = self.checkpoints.len().min(nr_lines / CHECKPOINT_INTERVAL + 1);
+    let (start, (mut highlight_state, mut parse_state)) = match usable.checked_sub(1) {
+      Some(index) => (index * CHECKPOINT_INTERVAL, self.checkpoints[index].clone()),
+      None => (0, (
+        HighlightState::new(&highlighter, ScopeStack::new()),
+        ParseState::new(syntax),
+      )),
+    };
+    for (linenr, line) in compared.into_iter().chain(lines).enumerate().skip(start) {
+      // Save a checkpoint when passing an interval we have none for
+      if linenr % CHECKPOINT_INTERVAL == 0 &&
+        linenr / CHECKPOINT_INTERVAL == self.checkpoints.len()
+      {
+        self.checkpoints.push((highlight_state.clone(), parse_state.clone()));
+      }
+      // Hash lines we haven't seen before, so later changes to them are found
+      if linenr >= self.line_hashes.len() {
+        self.line_hashes.push(hash_line(line));
+      }
+      // Without a snapshot the lines we read may have changed in place
+      else if snapshot.is_none() && self.line_hashes[linenr] != hash_line(line) {
+        self.line_hashes.truncate(linenr);
+        self.line_hashes.push(hash_line(line));
+        self.checkpoints.truncate(linenr / CHECKPOINT_INTERVAL + 1);
+      }
+      let ops = parse_state.parse_line(line, syntax_lib)?;
+      // The highlight state is only updated as the iterator is consumed
+      HighlightIterator::new(&mut highlight_state, &ops, line, &highlighter)
+        .for_each(drop);
+    }
+    Ok((highlight_state, parse_state))
+  }
+
+  // Find the first line that differs from what we have seen before, if any,
+  // and discard everything after it
+  fn invalidate_changed(&mut self, lines: &[&str]) {
+    let hashes: Vec<u64> = lines.iter().map(|line| hash_line(line)).collect();
+    let unchanged = hashes.iter()
+      .zip(&self.line_hashes)
+      .take_while(|(new, old)| new == old)
+      .count()
+    ;
+    if unchanged < hashes.len().min(self.line_hashes.len()) {
+      self.line_hashes.truncate(unchanged);
+      // A checkpoint is valid if all the lines before it are unchanged
+      self.checkpoints.truncate(unchanged / CHECKPOINT_INTERVAL + 1);
+    }
+    if hashes.len() > self.line_hashes.len() {
+      self.line_hashes.extend_from_slice(&hashes[self.line_hashes.len() ..]);
+    }
+  }
+}
+
+fn hash_line(line: &str) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  line.hash(&mut hasher);
+  hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // The state from highlighting the lines without cache, for comparison
+  fn fresh_state(
+    syntax_lib: &SyntaxSet,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    lines: &[String],
+  ) -> (HighlightState, ParseState) {
+    HighlightCache::default()
+      .state_after(syntax_lib, syntax, theme, None, lines.len(), lines.iter().map(|l| l.as_str()))
+      .unwrap()
+  }
+
+  #[test]
+  fn changes_are_found_on_new_snapshot() {
+    let syntax_lib = two_face::syntax::extra_newlines();
+    let syntax = syntax_lib.find_syntax_by_extension("rs").unwrap();
+    let theme = two_face::theme::extra().get(two_face::theme::EmbeddedThemeName::Base16).clone();
+    let mut lines: Vec<String> = (0 .. 600).map(|i| format!("let x{} = 1;\n", i)).collect();
+    let mut cache = HighlightCache::default();
+    let state = |cache: &mut HighlightCache, snapshot, lines: &[String]| {
+      cache.state_after(&syntax_lib, syntax, &theme, snapshot, lines.len(), lines.iter().map(|l| l.as_str()))
+        .unwrap()
+    };
+    assert_eq!(state(&mut cache, Some((1, 0)), &lines), fresh_state(&syntax_lib, syntax, &theme, &lines));
+    // Open a comment early in the buffer, in a new snapshot
+    lines[3] = "/*\n".to_string();
+    assert_eq!(state(&mut cache, Some((2, 1)), &lines), fresh_state(&syntax_lib, syntax, &theme, &lines));
+    // Changes in place, without snapshot, are found if in the lines read
+    lines[590] = "*/\n".to_string();
+    assert_eq!(state(&mut cache, None, &lines), fresh_state(&syntax_lib, syntax, &theme, &lines));
+  }
+}
@@ -0,0 +1,100 @@
+// Command history, which is saved between sessions if given a path
+use std::path::{Path, PathBuf};
+use std::io::Write; // Needed for write_all on the history file
+
+pub struct CommandHistory {
+  // Newline terminated commands, oldest first
+  entries: Vec<String>,
+  // File to load from and append to, if any
+  path: Option<PathBuf>,
+  // Max number of entries to keep
+  max_len: usize,
+}
+impl CommandHistory {
+  // Loads the history from the given path, if any
+  // Failing to read the history isn't worth refusing to start over, so we just
+  // start with an empty history in that case.
+  pub fn load(path: Option<PathBuf>, max_len: usize) -> Self {
+    let mut entries: Vec<String> = path.as_ref()
+      .and_then(|p| std::fs::read_to_string(p).ok())
+      .map(|s| s.lines().map(|l| format!("{}\n", l)).collect())
+      .unwrap_or_default()
+    ;
+    // Trim away the oldest entries if there are too many, both in memory and
+    // in the file (so it doesn't grow forever)
+    if entries.len() > max_len {
+      entries.drain(.. entries.len() - max_len);
+      if let Some(p) = &path {
+        std::fs::write(p, entries.concat()).ok();
+      }
+    }
+    Self{
+      entries,
+      path,
+      max_len,
+    }
+  }
+
+  pub fn entries(&self) -> &[String] {
+    &self.entries
+  }
+
+  // Adds a command to the history, unless it is identical to the latest entry
+  pub fn push(&mut self, command: String) {
+    if self.entries.last() == Some(&command) { return; }
+    // If writing fails we still have the command for this session, which is
+    // better than failing to run it.
+    if let Some(p) = &self.path {
+      append_to_file(p, &command).ok();
+    }
+    self.entries.push(command);
+    if self.entries.len() > self.max_len {
+      self.entries.remove(0);
+    }
+  }
+}
+
+fn append_to_file(path: &Path, command: &str) -> std::io::Result<()> {
+  // The data directory may not have been created yet
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  let mut file = std::fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)?
+  ;
+  // Exactly one newline per entry, so each line of the file is an entry
+  file.write_all(command.trim_end_matches('\n').as_bytes())?;
+  file.write_all(b"\n")?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn history_dedup_consecutive() {
+    let mut history = CommandHistory::load(None, 10);
+    history.push("p\n".to_string());
+    history.push("p\n".to_string());
+    history.push("n\n".to_string());
+    history.push("p\n".to_string());
+    assert_eq!(
+      history.entries(),
+      &["p\n".to_string(), "n\n".to_string(), "p\n".to_string()],
+    )
+  }
+  #[test]
+  fn history_max_len() {
+    let mut history = CommandHistory::load(None, 2);
+    history.push("1\n".to_string());
+    history.push("2\n".to_string());
+    history.push("3\n".to_string());
+    assert_eq!(
+      history.entries(),
+      &["2\n".to_string(), "3\n".to_string()],
+    )
+  }
+}
@@ -6,23 +6,259 @@ use crossterm::QueueableCommand;
 use crossterm::event::{KeyCode, KeyModifiers, Event};
 // And the writeable trait, to be able to flush stdout
 use std::io::Write;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use two_face::re_exports::syntect::{
+  highlighting::HighlightState,
+  parsing::{ParseState, SyntaxReference},
+};
 // Finally the error consts we use as error type
 use super::HUIError;
 type Result<T> = std::result::Result<T, HUIError>;
 
-// Since unicode is weird and this method is missing from str
-// Finds the nearest char boundary preceding given index and returns its index
-fn rfind_boundary(s: &str, i: usize) -> usize {
-  for b in (0 .. i).rev() {
-    if s.is_char_boundary(b) { return b; }
+// Max nr of undo steps to keep, older steps are dropped
+const MAX_UNDO_STEPS: usize = 100;
+
+// A state of the input buffer with its cursor, as kept for undo and redo
+type UndoState = (Vec<String>, (usize, usize));
+
+// Pushes a state onto the undo stack, dropping the oldest if over the limit
+fn push_undo(undo_stack: &mut Vec<UndoState>, state: UndoState) {
+  undo_stack.push(state);
+  if undo_stack.len() > MAX_UNDO_STEPS {
+    undo_stack.remove(0);
   }
-  0
 }
+
+// Since unicode is weird we move by grapheme cluster, so combining chars and
+// such are treated as part of the char they modify
+// Finds the nearest grapheme boundary preceding given index and returns its index
+fn rfind_boundary(s: &str, i: usize) -> usize {
+  s[.. i].grapheme_indices(true)
+    .next_back()
+    .map(|(b, _)| b)
+    .unwrap_or(0)
+}
+// Finds the nearest grapheme boundary following given index and returns its index
 fn find_boundary(s: &str, i: usize) -> usize {
-  for b in i + 1 .. s.len() + 1 {
-    if s.is_char_boundary(b) { return b; }
+  s[i ..].graphemes(true)
+    .next()
+    .map(|g| i + g.len())
+    .unwrap_or(i)
+}
+// Finds the index in the line at the given display column, or the line end if
+// the line is shorter (If the column is within a wide char we go before it)
+fn index_at_column(line: &str, column: usize, tab_width: usize) -> usize {
+  let mut width = 0;
+  for (index, g) in line.grapheme_indices(true) {
+    width += super::print::grapheme_width(g, width, tab_width);
+    if width > column { return index; }
+  }
+  line.len()
+}
+
+// Inserts text at the given cursor, returning the cursor after the text
+// In one-line mode newlines are flattened into spaces, otherwise the text is
+// split into lines.
+fn insert_text(
+  buffer: &mut Vec<String>,
+  (lindex, chindex): (usize, usize),
+  text: &str,
+  one_line: bool,
+) -> (usize, usize) {
+  if one_line {
+    let text = text.trim_end_matches('\n').replace('\n', " ");
+    buffer[lindex].insert_str(chindex, &text);
+    return (lindex, chindex + text.len());
+  }
+  // Put the text between the halves of the current line and split the result
+  // into lines
+  let tail = buffer[lindex].split_off(chindex);
+  let mut inserted = std::mem::take(&mut buffer[lindex]);
+  inserted.push_str(text);
+  let mut new_lines: Vec<String> = inserted.split_inclusive('\n')
+    .map(|line| line.to_string())
+    .collect()
+  ;
+  // If the text ended with a newline the cursor is on a new line
+  if inserted.ends_with('\n') || new_lines.is_empty() {
+    new_lines.push(String::new());
+  }
+  // Leave the cursor after the inserted text, before the old tail
+  let last = new_lines.len() - 1;
+  let new_chindex = new_lines[last].len();
+  new_lines[last].push_str(&tail);
+  let rest = buffer.split_off(lindex + 1);
+  buffer.pop();
+  buffer.extend(new_lines);
+  buffer.extend(rest);
+  (lindex + last, new_chindex)
+}
+// Removes the text between the given cursors, joining lines as needed
+fn remove_text(
+  buffer: &mut Vec<String>,
+  from: (usize, usize),
+  to: (usize, usize),
+) {
+  let tail = buffer[to.0].split_off(to.1);
+  buffer.drain(from.0 + 1 ..= to.0);
+  buffer[from.0].truncate(from.1);
+  buffer[from.0].push_str(&tail);
+}
+
+// What the last key press did, for keys whose behaviour depends on it
+enum LastAction {
+  Other,
+  // Consecutive kills are added to the same kill ring entry
+  Kill,
+  // Alt+Y replaces the text yanked, which starts at this cursor
+  Yank((usize, usize)),
+  // Tab cycles through the completion candidates
+  Complete,
+}
+
+// Word definitions for word movement and deletion
+// Alphanumeric words, as for readline's Alt+b/Alt+f
+fn is_word_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+// Whitespace separated words, as for readline's Ctrl+W
+fn is_non_whitespace(c: char) -> bool {
+  !c.is_whitespace()
+}
+// Finds the start of the word preceding the given index
+// (skips any non-word chars first, like readline)
+fn word_start_before(s: &str, i: usize, is_word: fn(char) -> bool) -> usize {
+  s[.. i]
+    .trim_end_matches(|c: char| !is_word(c))
+    .trim_end_matches(is_word)
+    .len()
+}
+// Finds the end of the word following the given index
+// (skips any non-word chars first, like readline)
+fn word_end_after(s: &str, i: usize, is_word: fn(char) -> bool) -> usize {
+  let rest = s[i ..]
+    .trim_start_matches(|c: char| !is_word(c))
+    .trim_start_matches(is_word)
+  ;
+  s.len() - rest.len()
+}
+
+// Get the syntax with given name, defaulting to plain text
+fn find_syntax<'a>(
+  state: &'a super::HighlightingUI,
+  name: Option<&str>,
+) -> &'a SyntaxReference {
+  name.and_then(|name| state.syntax_lib.find_syntax_by_name(name))
+    .unwrap_or_else(|| state.syntax_lib.find_syntax_plain_text())
+}
+
+// State for incremental search through the command history
+struct HistorySearch {
+  query: String,
+  // If true search towards older entries, else towards newer
+  reverse: bool,
+  // Index of the history entry currently matched, if any
+  found: Option<usize>,
+  // Index to continue searching from, starting in the present (history length)
+  pos: usize,
+}
+impl HistorySearch {
+  fn new(history_len: usize, reverse: bool) -> Self {
+    Self{
+      query: String::new(),
+      reverse: reverse,
+      found: None,
+      pos: history_len,
+    }
+  }
+  // Find the nearest entry containing the query, from pos (inclusive) in our
+  // direction
+  fn search(&mut self, history: &[String]) {
+    let query = self.query.as_str();
+    let hit = if self.reverse {
+      (0 .. (self.pos + 1).min(history.len())).rev()
+        .find(|&i| history[i].contains(query))
+    } else {
+      (self.pos .. history.len())
+        .find(|&i| history[i].contains(query))
+    };
+    if let Some(i) = hit { self.pos = i; }
+    self.found = hit;
+  }
+  // Find the next match past the current one in the given direction
+  // If there is none we keep the current match
+  fn step(&mut self, history: &[String], reverse: bool) {
+    let prior = (self.found, self.pos);
+    self.reverse = reverse;
+    if reverse {
+      if self.pos == 0 { return; }
+      self.pos -= 1;
+    }
+    else {
+      self.pos += 1;
+    }
+    self.search(history);
+    if self.found.is_none() {
+      (self.found, self.pos) = prior;
+    }
+  }
+  // The byte range of the query in the matched entry, for marking it
+  fn match_range(&self, line: &str) -> Option<Range<usize>> {
+    if self.query.is_empty() { return None; }
+    line.find(self.query.as_str()).map(|i| i .. i + self.query.len())
+  }
+}
+
+// Height of the line when printed without prefix, as internal_print wraps it
+// (A line exactly filling the terminal width is followed by an empty line)
+fn line_height(
+  state: &super::HighlightingUI,
+  line: &str,
+  tab_width: usize,
+) -> usize {
+  if !state.wrap { return 1; }
+  super::print::display_width(line, tab_width) / state.term_size.0.max(1) + 1
+}
+
+// Prints the preview lines numbered, with the matches or replacements marked
+// Returns the height of the print
+fn print_preview(
+  state: &super::HighlightingUI,
+  preview: &super::preview::Preview,
+) -> Result<u16> {
+  let mut height = 0;
+  let syntax = find_syntax(state, None);
+  for line in &preview.lines {
+    // Make sure the line is terminated, as the print expects
+    let mut text = line.text.clone();
+    if !text.ends_with('\n') { text.push('\n'); }
+    let marker = |_linenr: usize, _line: &str| line.marks.clone();
+    height += super::print::internal_print(
+      state,
+      &syntax,
+      &mut std::iter::once(('\0', &text[..])),
+      super::print::PrintConf {
+        prefix: None,
+        cursor: None,
+        start_line: 0,
+        first_line_nr: line.nr,
+        numbered: true,
+        literal: false,
+        initial_state: None,
+        separator: false,
+        wrap: state.wrap,
+        scroll: None,
+        marker: Some(&marker as &super::print::Marker),
+      },
+    ).map_err(HUIError::TerminalIOFailed)?.height;
+  }
+  if preview.truncated {
+    std::io::stdout().queue(crossterm::style::Print("...\n\r"))
+      .map_err(HUIError::TerminalIOFailed)?;
+    height += 1;
   }
-  i
+  Ok(height)
 }
 
 // This input getter runs get_event and buffers the input with expected editing features
@@ -30,17 +266,27 @@ fn find_boundary(s: &str, i: usize) -> usize {
 // A prefix can be given, which is then printed at start of every line and not included in input
 // A terminator can be given.
 // If given: input is returned after terminator has been entered alone on a line. Else on newline.
+// A syntax name can be given to highlight the input with, optionally with the
+// highlighting state after the lines preceding the input.
+// If an editor is given, substitutions and global commands typed are previewed
+// on its buffer.
 pub fn event_input(
   state: &mut super::HighlightingUI,
   initial_buffer: Vec<String>,
   prefix: Option<char>,
   terminator: Option<char>, // If none take only one line
+  syntax_name: Option<&str>, // If none highlight as plain text
+  context: Option<(HighlightState, ParseState)>,
+  preview_ed: Option<&add_ed::Ed>,
 ) -> Result<Vec<String>> {
   let mut stdout = std::io::stdout();
 
   // Set the cursor to be visible, so our moves are visible
   stdout.queue(crossterm::cursor::Show).map_err(HUIError::TerminalIOFailed)?;
 
+  // Tabs are as wide as when printing the input, for moving between lines
+  let tab_width = state.tab_width_for(find_syntax(state, syntax_name));
+
   // Set up buffer and variables for moving in it
   let mut buffer = initial_buffer;
   if buffer.len() == 0 { buffer.push("\n".to_string()); } // The buffer mustn't be empty
@@ -49,7 +295,7 @@ pub fn event_input(
 
   // Variable for tracking how many steps back in history
   // we are when moving back in history
-  let mut hoffset = state.command_history.len();
+  let mut hoffset = state.command_history.entries().len();
   // And one for keeping current input while moving about in history
   let mut semi_history = "\n".to_string();
 
@@ -58,43 +304,206 @@ pub fn event_input(
 
   // And if we are to return
   let mut ret = false; // Flag when ready to return
+  let mut interrupted = false; // Flag if returning because of Ctrl+C
+  let mut cancelled = false; // Flag if returning because of Esc
+
+  // If the input is taller than the terminal we edit it on the alternate
+  // screen, showing the lines around the cursor from the first visible line
+  let mut alternate = false;
+  let mut v_scroll = 0;
 
   // Then input specific variables
   let mut partial = String::with_capacity(4); // To store partial chars
 
   // and finally movement specific
-   // If we move via up/down to a line shorter than our current chindex that prior chindex is saved
-   // here, so we can go to that prior chindex if next operation is up/down. Else it's cleared.
-  let mut goal_chindex = None;
+   // If we move via up/down to a line shorter than our current column that prior column is saved
+   // here, so we can go to that prior column if next operation is up/down. Else it's cleared.
+  let mut goal_column = None;
+  // Nr of columns the cursor line is scrolled horizontally, when not wrapping
+  let mut h_scroll = 0;
+
+  // Undo and redo stacks of buffer states with their cursors
+  let mut undo_stack: Vec<UndoState> = Vec::new();
+  let mut redo_stack: Vec<UndoState> = Vec::new();
+  // If the last key inserted a character, so another should join its undo step
+  let mut grouping_inserts = false;
+
+  // What the last key press did, for kills and yanks
+  let mut last_action = LastAction::Other;
+
+  // Completions being tabbed through, with index of the one inserted if any
+  let mut completion: Option<(super::completion::Completions, Option<usize>)> = None;
+
+  // Incremental history search, if active (only in one-line mode)
+  let mut search: Option<HistorySearch> = None;
+
+  // The last preview, with the command it was made for
+  let mut preview: Option<(String, Option<super::preview::Preview>)> = None;
 
   // loop getting input events, ignoring those we can't handle.
   while !ret {
     // Print before blocking waiting for input
 
-    // Move up the cursor to overwrite prior input with this input
-    if (dists.height - dists.cursor_y) > 0 {
-      stdout.queue(crossterm::cursor::MoveUp(dists.height - dists.cursor_y))
+    if !alternate {
+      // Move up the cursor to overwrite prior input with this input
+      if (dists.height - dists.cursor_y) > 0 {
+        stdout.queue(crossterm::cursor::MoveUp(dists.height - dists.cursor_y))
+          .map_err(HUIError::TerminalIOFailed)?;
+      }
+      stdout.queue(crossterm::cursor::MoveToColumn(0)).map_err(HUIError::TerminalIOFailed)?;
+      // Clear away old print
+      stdout.queue(crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown))
+        .map_err(HUIError::TerminalIOFailed)?;
+      // Once the input would scroll out of view we switch to the alternate
+      // screen, since we can't move up to overwrite what has scrolled out
+      // (Only multi-line input can grow that tall)
+      if terminator.is_some() {
+        let height: usize = buffer.iter()
+          .map(|line| line_height(state, line, tab_width))
+          .sum()
+        ;
+        // Room for the separator and the empty line the print ends on
+        if height + 2 > state.term_size.1 {
+          stdout.queue(crossterm::terminal::EnterAlternateScreen)
+            .map_err(HUIError::TerminalIOFailed)?;
+          alternate = true;
+        }
+      }
+    }
+    if alternate {
+      // Redraw the whole screen instead
+      stdout.queue(crossterm::cursor::MoveTo(0, 0)).map_err(HUIError::TerminalIOFailed)?;
+      stdout.queue(crossterm::terminal::Clear(crossterm::terminal::ClearType::All))
         .map_err(HUIError::TerminalIOFailed)?;
     }
-    stdout.queue(crossterm::cursor::MoveToColumn(0)).map_err(HUIError::TerminalIOFailed)?;
-    // Clear away old print
-    stdout.queue(crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown))
-      .map_err(HUIError::TerminalIOFailed)?;
     // Then print
-    let syntax = state.syntax_lib.find_syntax_plain_text();
+    // If searching history we show the matched entry instead of the buffer
+    let found_entry = search.as_ref()
+      .and_then(|s| s.found)
+      .map(|i| state.command_history.entries()[i].clone())
+    ;
+    let (shown, cursor) = match (&search, &found_entry) {
+      (Some(s), Some(entry)) => (
+        std::slice::from_ref(entry),
+        (0, s.match_range(entry).map(|r| r.start).unwrap_or(0)),
+      ),
+      _ => (&buffer[..], (lindex, chindex)),
+    };
+    let search_marker = |_linenr: usize, line: &str| -> Vec<Range<usize>> {
+      search.as_ref()
+        .and_then(|s| s.match_range(line))
+        .into_iter()
+        .collect()
+    };
+    // When not wrapping, scroll the cursor line horizontally as needed to keep
+    // the cursor visible (leaving room for prefix and scroll markers)
+    if !state.wrap {
+      let cursor_column = super::print::display_width(&shown[cursor.0][.. cursor.1], tab_width);
+      let visible = state.term_size.0
+        .saturating_sub(prefix.map(|_| 1).unwrap_or(0) + 2)
+        .max(1)
+      ;
+      if cursor_column < h_scroll {
+        h_scroll = cursor_column;
+      }
+      else if cursor_column >= h_scroll + visible {
+        h_scroll = cursor_column + 1 - visible;
+      }
+    }
+    // On the alternate screen we only print the lines that fit, keeping the
+    // cursor line in view
+    let end = if alternate {
+      let available = state.term_size.1.saturating_sub(2).max(1);
+      let heights: Vec<usize> = shown.iter()
+        .map(|line| line_height(state, line, tab_width))
+        .collect()
+      ;
+      if cursor.0 < v_scroll { v_scroll = cursor.0; }
+      while v_scroll < cursor.0 && heights[v_scroll ..= cursor.0].iter().sum::<usize>() > available {
+        v_scroll += 1;
+      }
+      // Then fill the screen with as many lines as fit after the first
+      let mut end = v_scroll;
+      let mut used = 0;
+      while end < shown.len() && (end <= cursor.0 || used + heights[end] <= available) {
+        used += heights[end];
+        end += 1;
+      }
+      end
+    } else {
+      v_scroll = 0;
+      shown.len()
+    };
+    let syntax = find_syntax(state, syntax_name);
     dists = super::print::internal_print(
       state,
       &syntax,
-      &mut buffer.iter().map(|line| ('\0', &line[..])),
+      // Lines before v_scroll are only given for highlighting context
+      &mut shown[.. end].iter().map(|line| ('\0', &line[..])),
       super::print::PrintConf {
         prefix: prefix,
-        cursor: Some((lindex, chindex)),
-        start_line: 0,
+        cursor: Some((cursor.0 - v_scroll, cursor.1)),
+        start_line: v_scroll,
+        first_line_nr: 1,
         literal: false,
         numbered: false,
+        initial_state: context.clone(),
         separator: true,
+        wrap: state.wrap,
+        scroll: Some((cursor.0 - v_scroll, h_scroll)),
+        marker: search.as_ref().map(|_|
+          &search_marker as &super::print::Marker
+        ),
       },
     ).map_err(HUIError::TerminalIOFailed)?;
+    // Preview the command below it, unless searching
+    // To not lag behind on big buffers we only update the preview when there
+    // is no more input waiting, and only if the command changed
+    if let (Some(ed), None) = (preview_ed, &search) {
+      let pending = crossterm::event::poll(std::time::Duration::ZERO)
+        .map_err(HUIError::TerminalIOFailed)?
+      ;
+      if !pending && preview.as_ref().map(|(c, _)| c != &buffer[0]).unwrap_or(true) {
+        // Leave room for the command and some of the print before it
+        let max_lines = (state.term_size.1 / 2).max(1);
+        preview = Some((
+          buffer[0].clone(),
+          super::preview::preview_command(ed, &buffer[0], max_lines),
+        ));
+      }
+      if let Some((_, Some(p))) = &preview {
+        let height = print_preview(state, p)?;
+        dists.height += height;
+        dists.cursor_y += height;
+      }
+    }
+    // Show any status below the input, such as the search or completions
+    let status: Vec<String> = if let Some(s) = &search {
+      vec![format!(
+        "({}{}i-search)`{}': ",
+        if s.found.is_none() { "failing " } else { "" },
+        if s.reverse { "reverse-" } else { "" },
+        s.query,
+      )]
+    }
+    else if let Some((c, _)) = &completion {
+      super::completion::format_candidates(&c.candidates, state.term_size.0)
+    }
+    else {
+      Vec::new()
+    };
+    for (i, line) in status.iter().enumerate() {
+      // The print leaves us on an empty line, so we only add lines after the
+      // first (and must add them to the print size)
+      if i > 0 {
+        stdout.queue(crossterm::style::Print("\n\r")).map_err(HUIError::TerminalIOFailed)?;
+        dists.height += 1;
+        dists.cursor_y += 1;
+      }
+      // Cut lines to terminal width, since wrapping would throw off our sizes
+      let line = super::print::truncate_to_width(line, state.term_size.0.saturating_sub(1));
+      stdout.queue(crossterm::style::Print(line)).map_err(HUIError::TerminalIOFailed)?;
+    }
     // And move to the positions returned
     if dists.cursor_y > 0 {
       stdout.queue(crossterm::cursor::MoveUp(dists.cursor_y)).map_err(HUIError::TerminalIOFailed)?;
@@ -109,8 +518,32 @@ pub fn event_input(
       // If resize event, just update usize
       Event::Resize(x, y) => { state.term_size = (x as usize, y as usize); },
   
-      // Ignore paste events
-      Event::Paste(_) => (),
+      // Paste events insert all the pasted text in one go
+      // (This requires bracketed paste to be enabled, otherwise pasted text
+      // arrives as key events.)
+      Event::Paste(text) => {
+        partial.clear();
+        goal_column = None;
+        // Normalize line endings, as terminals often send '\r'
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        if let Some(s) = search.as_mut() {
+          s.query.push_str(&text.trim_end_matches('\n').replace('\n', " "));
+          s.search(state.command_history.entries());
+        }
+        else {
+          push_undo(&mut undo_stack, (buffer.clone(), (lindex, chindex)));
+          redo_stack.clear();
+          (lindex, chindex) = insert_text(
+            &mut buffer,
+            (lindex, chindex),
+            &text,
+            terminator.is_none(),
+          );
+        }
+        last_action = LastAction::Other;
+        grouping_inserts = false;
+        completion = None;
+      },
   
       // Ignore mouse events
       Event::Mouse(_) => (),
@@ -118,6 +551,51 @@ pub fn event_input(
       // Ignore focus events
       Event::FocusGained | Event::FocusLost => (),
   
+      // While searching history key events edit the search instead
+      Event::Key(key) if
+        key.kind == crossterm::event::KeyEventKind::Press &&
+        search.is_some()
+      => {
+        let history = state.command_history.entries();
+        // Unwrap is safe, since we check it in the match guard
+        let s = search.as_mut().unwrap();
+        match (key.code, key.modifiers) {
+          (KeyCode::Char('c'), KeyModifiers::CONTROL) | (KeyCode::Char('C'), KeyModifiers::CONTROL) => {
+            interrupted = true;
+            ret = true;
+          },
+          (KeyCode::Char(ch), KeyModifiers::SHIFT) | (KeyCode::Char(ch), KeyModifiers::NONE) => {
+            s.query.push(ch);
+            s.search(history);
+          },
+          (KeyCode::Backspace, KeyModifiers::NONE) | (KeyCode::Char('h'), KeyModifiers::CONTROL) => {
+            s.query.pop();
+            s.search(history);
+          },
+          (KeyCode::Char('r'), KeyModifiers::CONTROL) => { s.step(history, true); },
+          (KeyCode::Char('s'), KeyModifiers::CONTROL) => { s.step(history, false); },
+          // Accept the match into the buffer, for further editing
+          (KeyCode::Enter, _) => {
+            if let Some(i) = s.found {
+              // Save current input if in the present, same as for Up/Down
+              if hoffset == history.len() {
+                semi_history = buffer[0].clone();
+              }
+              hoffset = i;
+              buffer = vec![history[i].clone()];
+              lindex = 0;
+              chindex = buffer[0].len() - 1;
+            }
+            search = None;
+          },
+          // Cancel the search, leaving the buffer as it was
+          (KeyCode::Esc, _) | (KeyCode::Char('g'), KeyModifiers::CONTROL) => {
+            search = None;
+          },
+          _ => (), // Ignore unknown codes
+        }
+      },
+
       // If key event, match code and modifiers and handle thereafter
       Event::Key(key) if key.kind == crossterm::event::KeyEventKind::Press => {
         // Check if any of the state variables should be cleared
@@ -128,21 +606,77 @@ pub fn event_input(
           partial.clear();
         }
 
-        // If doing anything but moving up/down, clear goal_chindex
+        // Keys only affected by the last action look at prior_action
+        let prior_action = std::mem::replace(&mut last_action, LastAction::Other);
+
+        let is_insert = matches!(
+          (key.code, key.modifiers),
+          (KeyCode::Char(_), KeyModifiers::NONE) | (KeyCode::Char(_), KeyModifiers::SHIFT)
+        );
+        // Save the state before the key, to put on the undo stack if it edits
+        // (Not needed while typing, since a run of typing is one undo step, nor
+        // for keys that only move the cursor.)
+        let moves = matches!(
+          key.code,
+          KeyCode::Left | KeyCode::Right | KeyCode::Home | KeyCode::End
+        );
+        let before = if (is_insert && grouping_inserts) || moves {
+          None
+        } else {
+          Some((buffer.clone(), (lindex, chindex)))
+        };
+
+        // If doing anything but moving up/down, clear goal_column
         if (key.code != KeyCode::Up &&
           key.code != KeyCode::Down ) ||
           key.modifiers != KeyModifiers::NONE
         {
-          goal_chindex = None;
+          goal_column = None;
         }
   
         match (key.code, key.modifiers) {
 
           // If Ctrl+C is entered, abort input and return semi error "Interrupted"
           (KeyCode::Char('c'), KeyModifiers::CONTROL) | (KeyCode::Char('C'), KeyModifiers::CONTROL) => {
-            return Err(HUIError::Interrupted.into());
+            interrupted = true;
+            ret = true;
           },
   
+          // Undo/redo edits to the buffer
+          // (Ctrl+_ usually arrives as Ctrl+7, and Ctrl+Shift+Z as Ctrl+Z
+          // unless the terminal reports modifiers fully)
+          (KeyCode::Char('z'), KeyModifiers::CONTROL) |
+          (KeyCode::Char('_'), KeyModifiers::CONTROL) |
+          (KeyCode::Char('7'), KeyModifiers::CONTROL) => {
+            if let Some((prior, cursor)) = undo_stack.pop() {
+              redo_stack.push((std::mem::replace(&mut buffer, prior), (lindex, chindex)));
+              (lindex, chindex) = cursor;
+            }
+            grouping_inserts = false;
+            // Skip saving undo state, since this isn't an edit to undo
+            continue;
+          },
+          (KeyCode::Char('z'), modifiers) | (KeyCode::Char('Z'), modifiers)
+            if modifiers.contains(KeyModifiers::CONTROL)
+          => {
+            if let Some((next, cursor)) = redo_stack.pop() {
+              push_undo(&mut undo_stack, (std::mem::replace(&mut buffer, next), (lindex, chindex)));
+              (lindex, chindex) = cursor;
+            }
+            grouping_inserts = false;
+            continue;
+          },
+
+          // Ctrl+R/Ctrl+S starts searching back/forth in history, if in one-line mode
+          (KeyCode::Char('r'), KeyModifiers::CONTROL) | (KeyCode::Char('s'), KeyModifiers::CONTROL)
+            if terminator.is_none()
+          => {
+            search = Some(HistorySearch::new(
+              state.command_history.entries().len(),
+              key.code == KeyCode::Char('r'),
+            ));
+          },
+
           // Start with true input; characters and deletions
           (KeyCode::Char(ch), KeyModifiers::SHIFT) | (KeyCode::Char(ch), KeyModifiers::NONE) => {
             partial.push(ch);
@@ -154,6 +688,41 @@ pub fn event_input(
             }
           },
 
+          // In one-line mode Tab completes the word before the cursor
+          (KeyCode::Tab, KeyModifiers::NONE) if terminator.is_none() => {
+            let cycling = matches!(prior_action, LastAction::Complete);
+            match completion.as_mut().filter(|_| cycling) {
+              // Repeated Tab cycles through the candidates
+              Some((c, index)) => {
+                let next = index.map(|i| (i + 1) % c.candidates.len()).unwrap_or(0);
+                let replacement = &c.candidates[next].replacement;
+                buffer[lindex].replace_range(c.start .. chindex, replacement);
+                chindex = c.start + replacement.len();
+                *index = Some(next);
+                last_action = LastAction::Complete;
+              },
+              None => {
+                let c = super::completion::complete_command(
+                  &buffer[lindex][.. chindex],
+                  &state.macro_hints,
+                );
+                if let Some(c) = c.filter(|c| !c.candidates.is_empty()) {
+                  // Complete as far as all candidates agree
+                  let prefix = super::completion::common_prefix(&c.candidates).to_owned();
+                  if prefix.len() > chindex - c.start {
+                    buffer[lindex].replace_range(c.start .. chindex, &prefix);
+                    chindex = c.start + prefix.len();
+                  }
+                  // If ambiguous, list the candidates and cycle on next Tab
+                  if c.candidates.len() > 1 {
+                    completion = Some((c, None));
+                    last_action = LastAction::Complete;
+                  }
+                }
+              },
+            }
+          },
+
           (KeyCode::Tab, KeyModifiers::NONE) => {
             partial.push('\t');
             // If the partial is now complete, put it in the buffer
@@ -178,12 +747,37 @@ pub fn event_input(
             }
             else {
               // Just delete preceding character
+              let end = chindex;
               chindex = rfind_boundary(&buffer[lindex], chindex);
-              buffer[lindex].remove(chindex);
+              buffer[lindex].replace_range(chindex .. end, "");
             }
           },
   
-          (KeyCode::Delete, KeyModifiers::NONE) => {
+          // Ctrl+D on an empty command line quits, like EOF in classic ed
+          // (Returned as a 'q' command, so unsaved changes are checked for)
+          (KeyCode::Char('d'), KeyModifiers::CONTROL)
+            if terminator.is_none() && buffer[0] == "\n"
+          => {
+            buffer[0] = "q\n".to_string();
+            ret = true;
+          },
+          // In text input Ctrl+D finishes the input, as the terminator would
+          (KeyCode::Char('d'), KeyModifiers::CONTROL) if terminator.is_some() => {
+            // Drop the empty line we are on if it ends the input, as the
+            // terminator's line would be (even if it is the only line)
+            if lindex == buffer.len() - 1 && buffer[lindex] == "\n" {
+              buffer.pop();
+            }
+            ret = true;
+          },
+          // Esc cancels command input, without complaint
+          (KeyCode::Esc, KeyModifiers::NONE) if terminator.is_none() => {
+            cancelled = true;
+            ret = true;
+          },
+
+          // Otherwise Ctrl+D deletes, like in readline
+          (KeyCode::Delete, KeyModifiers::NONE) | (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
             if chindex >= buffer[lindex].len() - 1 {
               // Join this and following line
               // But only if there is a following line
@@ -196,10 +790,92 @@ pub fn event_input(
             }
             else {
               // Just delete following character
-              buffer[lindex].remove(chindex);
+              let line_end = buffer[lindex].len() - 1;
+              let end = find_boundary(&buffer[lindex][.. line_end], chindex);
+              buffer[lindex].replace_range(chindex .. end, "");
             }
           },
   
+          // Word and line deletion, readline style
+          // At line start/end they join lines, like Backspace/Delete
+          (KeyCode::Char('w'), KeyModifiers::CONTROL) |
+          (KeyCode::Backspace, KeyModifiers::ALT) |
+          (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
+            let mut killed = String::new();
+            if chindex == 0 {
+              if lindex != 0 {
+                let tmp = buffer.remove(lindex);
+                lindex -= 1;
+                killed = buffer[lindex].pop().map(String::from).unwrap_or_default();
+                chindex = buffer[lindex].len();
+                buffer[lindex].push_str(&tmp);
+              }
+            }
+            else {
+              let start = match key.code {
+                KeyCode::Char('w') => word_start_before(&buffer[lindex], chindex, is_non_whitespace),
+                KeyCode::Backspace => word_start_before(&buffer[lindex], chindex, is_word_char),
+                _ => 0,
+              };
+              killed = buffer[lindex].drain(start .. chindex).collect();
+              chindex = start;
+            }
+            // Put what was killed in the kill ring
+            if let LastAction::Kill = prior_action {
+              state.kill_ring.extend(&killed, true);
+            } else {
+              state.kill_ring.push(killed);
+            }
+            last_action = LastAction::Kill;
+          },
+
+          (KeyCode::Char('d'), KeyModifiers::ALT) |
+          (KeyCode::Char('k'), KeyModifiers::CONTROL) => {
+            let mut killed = String::new();
+            let line_end = buffer[lindex].len() - 1;
+            if chindex >= line_end {
+              if lindex != buffer.len() - 1 {
+                killed = buffer[lindex].pop().map(String::from).unwrap_or_default();
+                let tmp = buffer.remove(lindex + 1);
+                buffer[lindex].push_str(&tmp);
+              }
+            }
+            else {
+              let end = match key.code {
+                KeyCode::Char('d') => word_end_after(&buffer[lindex][.. line_end], chindex, is_word_char),
+                _ => line_end,
+              };
+              killed = buffer[lindex].drain(chindex .. end).collect();
+            }
+            // Put what was killed in the kill ring
+            if let LastAction::Kill = prior_action {
+              state.kill_ring.extend(&killed, false);
+            } else {
+              state.kill_ring.push(killed);
+            }
+            last_action = LastAction::Kill;
+          },
+
+          // Yank back the latest killed text
+          (KeyCode::Char('y'), KeyModifiers::CONTROL) => {
+            if let Some(text) = state.kill_ring.yank() {
+              let start = (lindex, chindex);
+              (lindex, chindex) = insert_text(&mut buffer, start, &text, terminator.is_none());
+              last_action = LastAction::Yank(start);
+            }
+          },
+
+          // Directly after a yank, replace the yanked text with the prior kill
+          (KeyCode::Char('y'), KeyModifiers::ALT) => {
+            if let LastAction::Yank(start) = prior_action {
+              if let Some(text) = state.kill_ring.yank_pop() {
+                remove_text(&mut buffer, start, (lindex, chindex));
+                (lindex, chindex) = insert_text(&mut buffer, start, &text, terminator.is_none());
+                last_action = LastAction::Yank(start);
+              }
+            }
+          },
+
           (KeyCode::Enter, KeyModifiers::NONE) | (KeyCode::Enter, KeyModifiers::CONTROL) => {
             // If only getting one line, return
             if terminator.is_none() {
@@ -263,11 +939,46 @@ pub fn event_input(
             }
           },
   
+          // Word movement, moving between lines at line start/end
+          (KeyCode::Right, KeyModifiers::CONTROL) | (KeyCode::Char('f'), KeyModifiers::ALT) => {
+            let line_end = buffer[lindex].len() - 1;
+            if chindex == line_end {
+              if buffer.len() - 1 > lindex {
+                lindex += 1;
+                chindex = 0;
+              }
+            }
+            else {
+              chindex = word_end_after(&buffer[lindex][.. line_end], chindex, is_word_char);
+            }
+          },
+
+          (KeyCode::Left, KeyModifiers::CONTROL) | (KeyCode::Char('b'), KeyModifiers::ALT) => {
+            if chindex == 0 {
+              if lindex > 0 {
+                lindex -= 1;
+                chindex = buffer[lindex].len() - 1;
+              }
+            }
+            else {
+              chindex = word_start_before(&buffer[lindex], chindex, is_word_char);
+            }
+          },
+
+          // Line start/end, readline style (Home/End go to buffer start/end)
+          (KeyCode::Char('a'), KeyModifiers::CONTROL) => {
+            chindex = 0;
+          },
+
+          (KeyCode::Char('e'), KeyModifiers::CONTROL) => {
+            chindex = buffer[lindex].len() - 1;
+          },
+
           (KeyCode::Up, KeyModifiers::NONE) | (KeyCode::Down, KeyModifiers::NONE) => {
             // Go back/forth in history if in one-line mode
             if terminator.is_none() {
               // If we are currently in the present, save state before moving
-              if hoffset == state.command_history.len() {
+              if hoffset == state.command_history.entries().len() {
                 // Save current input line as semi history, unwrap or shouldn't ever be needed
                 semi_history = buffer.pop().unwrap_or("\n".to_string());
               }
@@ -281,13 +992,13 @@ pub fn event_input(
                 },
                 KeyCode::Down => {
                   // If not in the present, move forward in history
-                  if hoffset < state.command_history.len() { hoffset += 1; }
+                  if hoffset < state.command_history.entries().len() { hoffset += 1; }
                 },
                 _ => (),
               }
               // Read that history entry into the buffer
               buffer.push(
-                state.command_history
+                state.command_history.entries()
                   .get(hoffset) // Get history at offset
                   .map(|line| line.clone()) // Convert from &str to String
                   .unwrap_or(semi_history.clone()) // If none we have arrived in the present
@@ -297,28 +1008,30 @@ pub fn event_input(
               chindex = buffer[0].len() - 1;
             }
             else {
+              // We keep the display column when moving between lines, rather
+              // than the byte index, since lines may have wide chars
+              let mut column = goal_column.unwrap_or_else(||
+                super::print::display_width(&buffer[lindex][.. chindex], tab_width)
+              );
               // First move to the indicated line, if possible
               match key.code {
                 KeyCode::Up => {
                   if lindex > 0 { lindex -= 1; }
-                  else { chindex = 0; }
+                  else { column = 0; }
                 },
                 KeyCode::Down => { 
                   if lindex < buffer.len() - 1 { lindex += 1; }
-                  // If on last line set chindex to max, so goal_chindex keeps cursor at EOL
-                  else { chindex = usize::MAX; }
+                  // If on last line set column to max, so goal_column keeps cursor at EOL
+                  else { column = usize::MAX; }
                 },
                 _ => (),
               }
-              // Then try to go to goal_chindex and place chindex within the new line
-              match goal_chindex {
-                Some(tmp) => { chindex = tmp; },
-                None => (),
-              }
-              // If current chindex is too big, save it as goal and go to nearest valid chindex
-              if chindex >= buffer[lindex].len() {
-                goal_chindex = Some(chindex);
-                chindex = buffer[lindex].len() - 1;
+              // Then place chindex as near the column as the new line allows
+              let line = &buffer[lindex][.. buffer[lindex].len() - 1];
+              chindex = index_at_column(line, column, tab_width);
+              // If the line is too short, save the column as goal for next move
+              if super::print::display_width(&line[.. chindex], tab_width) < column {
+                goal_column = Some(column);
               }
             }
           },
@@ -335,6 +1048,26 @@ pub fn event_input(
   
           _ => (), // Ignore unknown codes
         } // End of matching key-codes and modifiers
+
+        // If the buffer was edited, save the prior state for undo
+        // Consecutive character insertions are grouped into one undo step
+        match before {
+          Some(before) => {
+            let edited = buffer != before.0;
+            if edited {
+              push_undo(&mut undo_stack, before);
+              redo_stack.clear();
+            }
+            grouping_inserts = is_insert && edited;
+          },
+          // Continued typing, already in the undo step of the run
+          None if is_insert => (),
+          None => { grouping_inserts = false; },
+        }
+        // Completions are only kept (and shown) while tabbing through them
+        if !matches!(last_action, LastAction::Complete) {
+          completion = None;
+        }
       }, // End of Key input event matching
 
       // Ignore key release events, if the terminal even provides them
@@ -342,6 +1075,31 @@ pub fn event_input(
     } // End of event match
   } // End of while
 
+  // Go back to the normal screen, where the prior print was already cleared
+  if alternate {
+    stdout.queue(crossterm::terminal::LeaveAlternateScreen)
+      .map_err(HUIError::TerminalIOFailed)?;
+    dists = super::print::PrintData{ height: 0, cursor_y: 0, cursor_x: 0 };
+  }
+  // If interrupted we leave the input as it was shown
+  // (Unless that was on the alternate screen, then it is printed below.)
+  if interrupted && !alternate {
+    stdout.flush().map_err(HUIError::TerminalIOFailed)?;
+    return Err(HUIError::Interrupted.into());
+  }
+  // If cancelled we remove it, so a new prompt can take its place
+  if cancelled {
+    if (dists.height - dists.cursor_y) > 0 {
+      stdout.queue(crossterm::cursor::MoveUp(dists.height - dists.cursor_y))
+        .map_err(HUIError::TerminalIOFailed)?;
+    }
+    stdout.queue(crossterm::cursor::MoveToColumn(0)).map_err(HUIError::TerminalIOFailed)?;
+    stdout.queue(crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown))
+      .map_err(HUIError::TerminalIOFailed)?;
+    stdout.flush().map_err(HUIError::TerminalIOFailed)?;
+    return Err(HUIError::Cancelled.into());
+  }
+
   // Before returning print a clean print to leave in the buffer
 
   // Move up the cursor to overwrite prior input with this input
@@ -354,7 +1112,7 @@ pub fn event_input(
   stdout.queue(crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown))
     .map_err(HUIError::TerminalIOFailed)?;
   // Then print
-  let syntax = state.syntax_lib.find_syntax_plain_text();
+  let syntax = find_syntax(state, syntax_name);
   super::print::internal_print(
     state,
     &syntax,
@@ -363,12 +1121,79 @@ pub fn event_input(
       prefix: prefix,
       cursor: None,
       start_line: 0,
+      first_line_nr: 1,
       numbered: false,
       literal: false,
+      initial_state: context,
       separator: true,
+      wrap: state.wrap,
+      scroll: None,
+      marker: None,
     },
   ).map_err(HUIError::TerminalIOFailed)?;
   // Then flush and return
   stdout.flush().map_err(HUIError::TerminalIOFailed)?;
+  if interrupted {
+    return Err(HUIError::Interrupted.into());
+  }
   Ok(buffer)
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn word_movement() {
+    let s = "s/foo_bar/baz qux/g";
+    assert_eq!(word_start_before(s, s.len(), is_word_char), 18);
+    assert_eq!(word_start_before(s, 18, is_word_char), 14);
+    assert_eq!(word_start_before(s, 10, is_word_char), 2);
+    assert_eq!(word_end_after(s, 0, is_word_char), 1);
+    assert_eq!(word_end_after(s, 1, is_word_char), 9);
+    assert_eq!(word_end_after(s, 18, is_word_char), s.len());
+  }
+  #[test]
+  fn whitespace_word_deletion() {
+    let s = "w !cat -n  ";
+    assert_eq!(word_start_before(s, s.len(), is_non_whitespace), 7);
+    assert_eq!(word_start_before(s, 7, is_non_whitespace), 2);
+    assert_eq!(word_start_before("åäö", 6, is_non_whitespace), 0);
+  }
+  #[test]
+  fn undo_steps_are_limited() {
+    let mut undo_stack = Vec::new();
+    for i in 0 .. MAX_UNDO_STEPS + 5 {
+      push_undo(&mut undo_stack, (vec![format!("{}\n", i)], (0, 0)));
+    }
+    assert_eq!(undo_stack.len(), MAX_UNDO_STEPS);
+    assert_eq!(undo_stack[0].0, vec!["5\n".to_owned()]);
+  }
+  #[test]
+  fn grapheme_movement() {
+    // Decomposed "å" (a and combining ring) is one grapheme of 3 bytes
+    let s = "ba\u{30A}r";
+    assert_eq!(find_boundary(s, 1), 4);
+    assert_eq!(rfind_boundary(s, 4), 1);
+    assert_eq!(find_boundary(s, s.len()), s.len());
+  }
+  #[test]
+  fn wide_char_columns() {
+    // Japanese chars are 2 columns wide and 3 bytes
+    let s = "日本語x";
+    assert_eq!(index_at_column(s, 0, 4), 0);
+    assert_eq!(index_at_column(s, 3, 4), 3);
+    assert_eq!(index_at_column(s, 4, 4), 6);
+    assert_eq!(index_at_column(s, 6, 4), 9);
+    assert_eq!(index_at_column(s, 10, 4), s.len());
+  }
+  #[test]
+  fn tab_stop_columns() {
+    // The tab extends from column 1 to the tab stop at 4
+    let s = "a\tb";
+    assert_eq!(index_at_column(s, 2, 4), 1);
+    assert_eq!(index_at_column(s, 4, 4), 2);
+    assert_eq!(index_at_column(s, 4, 8), 1);
+    assert_eq!(super::super::print::display_width("ab\tc\td\n", 4), 9);
+  }
+}
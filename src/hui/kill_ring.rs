@@ -0,0 +1,46 @@
+// Ring of texts removed by kill commands in input, for yanking back
+// Shared between all inputs during a session, like the command history
+
+// Max nr of entries to keep
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Default)]
+pub struct KillRing {
+  // Killed texts, oldest first
+  entries: Vec<String>,
+  // How many steps back from the newest entry the last yank was
+  yank_offset: usize,
+}
+impl KillRing {
+  // Adds killed text as a new entry
+  pub fn push(&mut self, text: String) {
+    if text.is_empty() { return; }
+    self.entries.push(text);
+    if self.entries.len() > MAX_ENTRIES {
+      self.entries.remove(0);
+    }
+  }
+  // Adds killed text to the newest entry, for consecutive kills
+  // Killing backwards prepends, since that text came before the prior kill
+  pub fn extend(&mut self, text: &str, backwards: bool) {
+    match self.entries.last_mut() {
+      Some(last) => if backwards {
+        last.insert_str(0, text);
+      } else {
+        last.push_str(text);
+      },
+      None => self.push(text.to_owned()),
+    }
+  }
+  // Gets the newest entry
+  pub fn yank(&mut self) -> Option<String> {
+    self.yank_offset = 0;
+    self.entries.last().cloned()
+  }
+  // Gets the entry before the one last yanked, wrapping around to the newest
+  pub fn yank_pop(&mut self) -> Option<String> {
+    if self.entries.is_empty() { return None; }
+    self.yank_offset = (self.yank_offset + 1) % self.entries.len();
+    self.entries.get(self.entries.len() - 1 - self.yank_offset).cloned()
+  }
+}
@@ -1,7 +1,8 @@
 use crossterm::QueueableCommand;
-use two_face::re_exports::syntect::parsing::SyntaxSet;
+use two_face::re_exports::syntect::parsing::{SyntaxSet, SyntaxReference};
 use two_face::re_exports::syntect::highlighting::Theme;
 use std::io::stdout;
+use std::cell::RefCell;
 
 // use the UI trait, to implement it
 use add_ed::ui::{
@@ -19,6 +20,13 @@ use add_ed::{
 mod print;
 mod doc_print;
 mod input;
+mod history;
+mod kill_ring;
+mod completion;
+mod preview;
+mod search;
+mod highlight_cache;
+mod syntax_detect;
 pub mod error;
 use error::HighlightingUIError as HUIError;
 
@@ -26,21 +34,373 @@ pub struct HighlightingUI {
   syntax_lib: SyntaxSet,
   theme: Theme,
   term_size: (usize, usize),
-  command_history: Vec<String>,
+  command_history: history::CommandHistory,
+  kill_ring: kill_ring::KillRing,
+  // In a RefCell, so it can be updated while syntax references are held
+  highlight_cache: RefCell<highlight_cache::HighlightCache>,
+  // Pairs of file glob and syntax to use for matching files, most specific first
+  syntax_overrides: Vec<(String, String)>,
+  // Syntax to use regardless of file, set at runtime
+  forced_syntax: Option<String>,
+  // Width of tab stops, default and by syntax name
+  tab_width: usize,
+  syntax_tab_widths: std::collections::HashMap<String, usize>,
+  // Names and descriptions of the available macros, for completion
+  macro_hints: Vec<(String, String)>,
+  // If substitutions and global commands are previewed while typed
+  preview: bool,
+  // If long lines are wrapped, else they are cut off at terminal width
+  wrap: bool,
+  // How to mark regex matches when printing
+  match_style: crate::config::MatchStyle,
+  // The regex last searched for, whose matches are marked when printing
+  last_search: Option<regex::Regex>,
+  // The command last given to the editor, to know where input it asks for goes
+  last_command: String,
 }
 impl HighlightingUI {
-  pub fn new() -> Self {
-    let theme: Theme = two_face::theme::extra().get(two_face::theme::EmbeddedThemeName::Base16).clone();
-    let syntax: SyntaxSet = two_face::syntax::extra_newlines();
-    Self{
+  // Macro hints are pairs of macro name and description, for completion
+  pub fn new(
+    config: &crate::config::Config,
+    macro_hints: Vec<(String, String)>,
+  ) -> std::result::Result<Self, HUIError> {
+    let theme: Theme = match &config.theme {
+      Some(name) => load_theme(name, &config.config_dir)?,
+      None => two_face::theme::extra().get(two_face::theme::EmbeddedThemeName::Base16).clone(),
+    };
+    let syntax: SyntaxSet = load_syntaxes(
+      &config.config_dir.join("syntaxes"),
+      &config.data_dir,
+    )?;
+    // Verify that the overrides are valid, so we don't silently ignore them
+    for name in config.syntax_overrides.values() {
+      if syntax.find_syntax_by_token(name).is_none() {
+        return Err(HUIError::UnknownSyntax(name.clone()));
+      }
+    }
+    // Treat longer globs as more specific, so they take priority
+    let mut syntax_overrides: Vec<(String, String)> = config.syntax_overrides.clone()
+      .into_iter()
+      .collect()
+    ;
+    syntax_overrides.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+    // Tab widths are given by syntax name or extension, look up the name
+    let mut syntax_tab_widths = std::collections::HashMap::new();
+    for (name, width) in &config.syntax_tab_widths {
+      let found = syntax.find_syntax_by_token(name)
+        .ok_or_else(|| HUIError::UnknownSyntax(name.clone()))
+      ?;
+      syntax_tab_widths.insert(found.name.clone(), (*width).max(1));
+    }
+    Ok(Self{
       syntax_lib: syntax,
       theme: theme,
       term_size: crossterm::terminal::size().map(|(a,b)| (a as usize, b as usize)).unwrap_or((80,24)),
-      command_history: Vec::new(),
+      command_history: history::CommandHistory::load(
+        config.history_path.clone(),
+        config.history_size,
+      ),
+      kill_ring: kill_ring::KillRing::default(),
+      highlight_cache: RefCell::new(highlight_cache::HighlightCache::default()),
+      syntax_overrides: syntax_overrides,
+      forced_syntax: None,
+      tab_width: config.tab_width.max(1),
+      syntax_tab_widths: syntax_tab_widths,
+      macro_hints: macro_hints,
+      preview: config.preview,
+      wrap: config.wrap,
+      match_style: config.match_style,
+      last_search: None,
+      last_command: String::new(),
+    })
+  }
+
+  // Get the syntax to highlight the buffer with
+  fn syntax_for(&self, ed: &Ed) -> &SyntaxReference {
+    let buffer = ed.history.current();
+    let len = buffer.len();
+    // Modelines are looked for in the first and last lines of the buffer
+    let head: Vec<&str> = buffer.get_tagged_lines((1, len.min(5)))
+      .map(|iter| iter.map(|line| line.1).collect())
+      .unwrap_or_default()
+    ;
+    let tail: Vec<&str> = buffer.get_tagged_lines((len.saturating_sub(4).max(1), len))
+      .map(|iter| iter.map(|line| line.1).collect())
+      .unwrap_or_default()
+    ;
+    syntax_detect::detect_syntax(
+      &self.syntax_lib,
+      syntax_detect::SyntaxSources{
+        forced: self.forced_syntax.as_deref(),
+        overrides: &self.syntax_overrides,
+        file: &ed.file,
+        head: &head,
+        tail: &tail,
+      },
+    )
+  }
+
+  // Identify the buffer state, for the highlight cache
+  // The history only grows (undo adds a snapshot reverting to the old state),
+  // so the nr of snapshots and the viewed one identify the buffer state.
+  // Returns None while the editor changes the buffer without snapshots (such
+  // as in macros and global commands), since it then changes in place.
+  fn snapshot_key(&self, ed: &Ed) -> Option<highlight_cache::SnapshotKey> {
+    if ed.history.dont_snapshot { return None; }
+    Some((ed.history.len(), ed.history.viewed_i()))
+  }
+
+  // Get the width of tab stops when printing with the given syntax
+  fn tab_width_for(&self, syntax: &SyntaxReference) -> usize {
+    self.syntax_tab_widths.get(&syntax.name)
+      .copied()
+      .unwrap_or(self.tab_width)
+  }
+
+  // Handles commands meant for the UI rather than the editor
+  // Returns false if the command wasn't a UI command
+  // (Since they never reach the editor they can't be run from macros.)
+  fn run_ui_command(&mut self, command: &str) -> Result<bool> {
+    let command = command.trim();
+    let (name, argument) = command.split_once(char::is_whitespace)
+      .map(|(name, argument)| (name, argument.trim()))
+      .unwrap_or((command, ""))
+    ;
+    // Keep in sync with UI_COMMANDS
+    match name.strip_prefix(':').unwrap_or("") {
+      // Force a syntax for this session, or with no argument go back to
+      // detecting it
+      "syntax" => {
+        if argument.is_empty() {
+          self.forced_syntax = None;
+        }
+        else {
+          match self.syntax_lib.find_syntax_by_token(argument) {
+            Some(syntax) => {
+              self.forced_syntax = Some(syntax.name.clone());
+            },
+            None => {
+              self.print_message(&format!("Unknown syntax '{}'.", argument))?;
+            },
+          }
+        }
+        Ok(true)
+      },
+      // Toggle previewing of substitutions and global commands
+      "preview" => {
+        self.preview = !self.preview;
+        self.print_message(&format!(
+          "Preview {}.",
+          if self.preview { "enabled" } else { "disabled" },
+        ))?;
+        Ok(true)
+      },
+      // Toggle wrapping of long lines
+      "wrap" => {
+        self.wrap = !self.wrap;
+        self.print_message(&format!(
+          "Line wrapping {}.",
+          if self.wrap { "enabled" } else { "disabled" },
+        ))?;
+        Ok(true)
+      },
+      // Stop marking matches of the last search, until next search
+      "nohighlight" => {
+        self.last_search = None;
+        Ok(true)
+      },
+      _ => Ok(false),
     }
   }
 }
 
+// Names of the UI commands, which are given like macro calls (`:name`)
+// Macros with these names could never be run, so they are left out.
+pub const UI_COMMANDS: &[&str] = &["syntax", "preview", "wrap", "nohighlight"];
+
+// Check the parts of the configuration only the UI can validate
+// Returns every problem found, with the key path of the configuration it was
+// found at (empty for problems outside the configuration file)
+pub fn check_config(
+  config: &crate::config::Config,
+) -> Vec<(Vec<String>, HUIError)> {
+  let mut problems = Vec::new();
+  if let Some(name) = &config.theme {
+    if let Err(e) = load_theme(name, &config.config_dir) {
+      problems.push((vec!["theme".to_owned()], e));
+    }
+  }
+  let mut macros: Vec<&String> = config.macros.keys()
+    .filter(|name| UI_COMMANDS.contains(&name.as_str()))
+    .collect()
+  ;
+  macros.sort();
+  for name in macros {
+    problems.push((
+      vec!["macros".to_owned(), name.clone()],
+      HUIError::MacroHidden(name.clone()),
+    ));
+  }
+  let syntax = match load_syntaxes(
+    &config.config_dir.join("syntaxes"),
+    &config.data_dir,
+  ) {
+    Ok(syntax) => syntax,
+    Err(e) => {
+      problems.push((Vec::new(), e));
+      return problems;
+    },
+  };
+  let mut overrides: Vec<(&String, &String)> = config.syntax_overrides.iter().collect();
+  overrides.sort();
+  for (glob, name) in overrides {
+    if syntax.find_syntax_by_token(name).is_none() {
+      problems.push((
+        vec!["syntax_overrides".to_owned(), glob.clone()],
+        HUIError::UnknownSyntax(name.clone()),
+      ));
+    }
+  }
+  let mut tab_widths: Vec<&String> = config.syntax_tab_widths.keys().collect();
+  tab_widths.sort();
+  for name in tab_widths {
+    if syntax.find_syntax_by_token(name).is_none() {
+      problems.push((
+        vec!["syntax_tab_widths".to_owned(), name.clone()],
+        HUIError::UnknownSyntax(name.clone()),
+      ));
+    }
+  }
+  problems
+}
+
+// If the theme is given as a path to a theme file, rather than a theme name
+pub fn is_theme_path(theme: &str) -> bool {
+  theme.ends_with(".tmTheme") || theme.contains(std::path::MAIN_SEPARATOR)
+}
+
+// Get the theme by embedded name, or if it looks like a path load it from file
+// (Names are matched case insensitively, relative paths from config_dir)
+fn load_theme(
+  name: &str,
+  config_dir: &std::path::Path,
+) -> std::result::Result<Theme, HUIError> {
+  use two_face::theme::EmbeddedLazyThemeSet;
+  if is_theme_path(name) {
+    return two_face::re_exports::syntect::highlighting::ThemeSet::get_theme(
+      config_dir.join(name)
+    )
+      .map_err(|e| HUIError::ThemeLoadFailed(name.to_owned(), e))
+  }
+  EmbeddedLazyThemeSet::theme_names().iter()
+    .find(|theme| theme.as_name().eq_ignore_ascii_case(name))
+    .map(|theme| two_face::theme::extra().get(*theme).clone())
+    .ok_or_else(|| HUIError::UnknownTheme(name.to_owned()))
+}
+
+// Get the embedded syntaxes, extended with any .sublime-syntax files in
+// syntax_dir
+// Since building the extended set is slow we cache it as a dump in cache_dir,
+// which is used until something in syntax_dir is modified.
+fn load_syntaxes(
+  syntax_dir: &std::path::Path,
+  cache_dir: &std::path::Path,
+) -> std::result::Result<SyntaxSet, HUIError> {
+  use two_face::re_exports::syntect::dumps;
+  if !syntax_dir.is_dir() {
+    return Ok(two_face::syntax::extra_newlines());
+  }
+  // Include version, since the embedded syntaxes may change between versions
+  let cache_path = cache_dir.join(
+    concat!("syntaxes-", env!("CARGO_PKG_VERSION"), ".packdump")
+  );
+  let cache_time = std::fs::metadata(&cache_path).and_then(|m| m.modified()).ok();
+  if let (Some(cache_time), Some(syntax_time)) = (cache_time, newest_modification(syntax_dir)) {
+    if syntax_time <= cache_time {
+      if let Ok(syntax) = dumps::from_dump_file::<SyntaxSet, _>(&cache_path) {
+        return Ok(syntax);
+      }
+    }
+  }
+  let mut builder = two_face::syntax::extra_newlines().into_builder();
+  builder.add_from_folder(syntax_dir, true)
+    .map_err(|e| HUIError::SyntaxLoadFailed(syntax_dir.display().to_string(), e))
+  ?;
+  let syntax = builder.build();
+  // Failing to cache only costs us time on next startup, so ignore errors
+  std::fs::create_dir_all(cache_dir).ok();
+  dumps::dump_to_file(&syntax, &cache_path).ok();
+  Ok(syntax)
+}
+// Newest modification time of anything in the given directory (including it)
+// Returns None if any of it couldn't be read
+fn newest_modification(path: &std::path::Path) -> Option<std::time::SystemTime> {
+  let mut newest = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+  if path.is_dir() {
+    for entry in std::fs::read_dir(path).ok()? {
+      newest = newest.max(newest_modification(&entry.ok()?.path())?);
+    }
+  }
+  Some(newest)
+}
+
+// The name of the macro a command runs and how many arguments it is given
+// Split on single spaces as add-ed does, so repeated spaces give empty
+// arguments.
+fn macro_call(command: &str) -> Option<(&str, usize)> {
+  let command = command.trim_end_matches('\n');
+  let (index, ch) = completion::find_command(command)?;
+  if ch != ':' { return None; }
+  let mut args = command[index + 1 ..].split(' ');
+  let name = args.next()?;
+  Some((name, args.count()))
+}
+
+// The nr of lines before where the input to the given command goes
+// The editor asks for input before it selects what the command targets, so we
+// find that from the command. Returns None for commands we don't understand.
+fn lines_before_input(
+  command: &str,
+  selection: (usize, usize),
+  len: usize,
+) -> Option<usize> {
+  let command = command.trim_end_matches('\n');
+  let (index, ch) = completion::find_command(command)?;
+  // When inserting add-ed takes . as the end of the selection if appending,
+  // else as its start
+  let current = match ch {
+    'a' | 'A' => (selection.1, selection.1),
+    'i' | 'I' => (selection.0, selection.0),
+    'c' | 'C' => selection,
+    _ => return None,
+  };
+  let (start, end) = preview::parse_selection(&command[.. index], current, len)?;
+  Some(match ch {
+    'a' => end,
+    // The first line of input is joined onto the line appended to
+    'A' => end - 1,
+    _ => start - 1,
+  })
+}
+
+// If the command runs a macro with a nr of arguments it doesn't take, returns
+// an error message showing the macro's definition
+// (Unknown macros and macros that fail to load are left to the editor.)
+fn macro_usage_error(ed: &Ed, command: &str) -> Option<String> {
+  let (name, count) = macro_call(command)?;
+  let m = ed.macro_getter.get_macro(name).ok()??;
+  if crate::macro_store::accepts_nr_arguments(&m.nr_arguments, count) {
+    return None;
+  }
+  Some(format!(
+    "Macro '{}' takes {}, but was given {}. Its definition is:\n{}",
+    name,
+    crate::macro_store::describe_nr_arguments(&m.nr_arguments),
+    count,
+    crate::macro_store::describe_definition(name, m).trim_end(),
+  ))
+}
+
 use std::io::Write; // Needed for the queue and flush functions on stdout
 
 impl UI for HighlightingUI {
@@ -79,32 +439,86 @@ impl UI for HighlightingUI {
   }
   fn get_command(
     &mut self,
-    _ed: &Ed,
+    ed: &Ed,
     prefix: Option<char>,
   ) -> Result<String> {
-    let command = input::event_input(
+    // Loop until we get a command for the editor, handling UI commands
+    loop {
+      let preview_ed = if self.preview { Some(ed) } else { None };
+      let command = match input::event_input(
         self,
         Vec::new(),
         prefix,
         None, // We want one line specifically
-      )
-        .map_err(|e|add_ed::EdError::UI(e.into()))?
-        .remove(0)
-    ;
-    self.command_history.push(command.clone());
-    Ok(command)
+        None, // Commands are highlighted as plain text
+        None,
+        preview_ed,
+      ) {
+        Ok(mut lines) => lines.remove(0),
+        // Esc cancels the command, so we just prompt again
+        Err(HUIError::Cancelled) => continue,
+        Err(e) => return Err(add_ed::EdError::UI(e.into())),
+      };
+      self.command_history.push(command.clone());
+      // Remember searches, to mark their matches when printing
+      // (If the regex is invalid the editor will say so, we just ignore it)
+      if let Some(regex) = search::search_regex(&command) {
+        // Built as add-ed builds it, so ^ and $ match at each line
+        if let Ok(regex) = regex::RegexBuilder::new(&regex).multi_line(true).build() {
+          self.last_search = Some(regex);
+        }
+      }
+      if self.run_ui_command(&command)? {
+        continue;
+      }
+      // Catch macro calls with the wrong nr of arguments, to show how the
+      // macro should be used
+      if let Some(usage) = macro_usage_error(ed, &command) {
+        self.print_message(&usage)?;
+        continue;
+      }
+      self.last_command = command.clone();
+      return Ok(command);
+    }
   }
   fn get_input(
     &mut self,
-    _ed: &Ed,
+    ed: &Ed,
     terminator: char,
     initial_buffer: Option<Vec<String>>,
   ) -> Result<Vec<String>> {
+    // Highlight input as the buffer, with the lines preceding where the input
+    // goes as context (if we can't tell where, those preceding the selection)
+    let snapshot = self.snapshot_key(ed);
+    let syntax = self.syntax_for(ed);
+    let nr_lines = lines_before_input(
+      &self.last_command,
+      ed.selection,
+      ed.history.current().len(),
+    ).unwrap_or(ed.selection.0.saturating_sub(1));
+    let context = ed.history.current()
+      .get_tagged_lines((1, nr_lines))
+      .ok()
+      .map(|iter| self.highlight_cache.borrow_mut().state_after(
+        &self.syntax_lib,
+        syntax,
+        &self.theme,
+        snapshot,
+        nr_lines,
+        iter.map(|line| line.1),
+      ))
+      .transpose()
+      .map_err(|e| add_ed::EdError::UI(HUIError::HighlightingFailed(e).into()))?
+    ;
+    let syntax_name = syntax.name.clone();
     input::event_input(
       self,
       initial_buffer.unwrap_or(Vec::new()),
       None, // No line prefix for input
-      Some(terminator)
+      Some(terminator),
+      Some(&syntax_name),
+      context,
+      None, // Input isn't a command, so nothing to preview
     )
       .map_err(|e|add_ed::EdError::UI(e.into()))
   }
@@ -116,10 +530,30 @@ impl UI for HighlightingUI {
     literal: bool,
   ) -> Result<()> {
     // First we get the data needed to call the internal function
-    let mut iter = ed.history.current().get_tagged_lines(selection)?;
-    let syntax = self.syntax_lib.find_syntax_for_file(&ed.file)
-      .unwrap_or(None)
-      .unwrap_or_else(|| self.syntax_lib.find_syntax_plain_text());
+    // We get all lines from buffer start, so the highlighter gets the lines
+    // preceding the selection as context
+    let mut iter = ed.history.current().get_tagged_lines((1, selection.1))?;
+    let snapshot = self.snapshot_key(ed);
+    let syntax = self.syntax_for(ed);
+    // Get the highlighting state after the preceding lines from the cache
+    let nr_lines = selection.0.saturating_sub(1);
+    let state = self.highlight_cache.borrow_mut().state_after(
+      &self.syntax_lib,
+      syntax,
+      &self.theme,
+      snapshot,
+      nr_lines,
+      (&mut iter).take(nr_lines).map(|line| line.1),
+    )
+      .map_err(|e| add_ed::EdError::UI(HUIError::HighlightingFailed(e).into()))?
+    ;
+    // Mark the matches of the last search, if any
+    let search_marker = |_linenr: usize, line: &str| -> Vec<std::ops::Range<usize>> {
+      match &self.last_search {
+        Some(regex) => regex.find_iter(line).map(|m| m.range()).collect(),
+        None => Vec::new(),
+      }
+    };
     // Then we call the internal print
     print::internal_print(
       &self,
@@ -128,10 +562,15 @@ impl UI for HighlightingUI {
       print::PrintConf {
         prefix: None,
         cursor: None,
-        start_line: selection.0,
+        start_line: 0,
+        first_line_nr: selection.0,
         numbered: numbered,
         literal: literal,
+        initial_state: Some(state),
         separator: true,
+        wrap: self.wrap,
+        scroll: None,
+        marker: Some(&search_marker as &print::Marker),
       },
     )
       .map_err(HUIError::TerminalIOFailed)
@@ -141,6 +580,11 @@ impl UI for HighlightingUI {
   }
   fn lock_ui(&mut self) -> UILock {
     // Before handing over to shell escaped commands we need to disable raw mode
+    // (and bracketed paste, which is only meant to be enabled with raw mode)
+    crossterm::execute!(stdout(), crossterm::event::DisableBracketedPaste)
+      .map_err(HUIError::TerminalIOFailed)
+      .unwrap()
+    ;
     crossterm::terminal::disable_raw_mode()
       .map_err(HUIError::RawmodeSwitchFailed)
       .unwrap()
@@ -153,5 +597,34 @@ impl UI for HighlightingUI {
       .map_err(HUIError::RawmodeSwitchFailed)
       .unwrap()
     ;
+    crossterm::execute!(stdout(), crossterm::event::EnableBracketedPaste)
+      .map_err(HUIError::TerminalIOFailed)
+      .unwrap()
+    ;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn macro_arguments_split_on_single_spaces() {
+    assert_eq!(macro_call(":grep foo\n"), Some(("grep", 1)));
+    assert_eq!(macro_call("1,5:grep foo  bar"), Some(("grep", 3)));
+    assert_eq!(macro_call(":grep"), Some(("grep", 0)));
+    assert_eq!(macro_call("p"), None);
+  }
+  #[test]
+  fn input_context_from_command() {
+    assert_eq!(lines_before_input("a\n", (3, 5), 10), Some(5));
+    assert_eq!(lines_before_input(".a", (3, 5), 10), Some(5));
+    assert_eq!(lines_before_input("2,7a", (3, 5), 10), Some(7));
+    assert_eq!(lines_before_input("i", (3, 5), 10), Some(2));
+    assert_eq!(lines_before_input("8i", (3, 5), 10), Some(7));
+    assert_eq!(lines_before_input("4A", (3, 5), 10), Some(3));
+    assert_eq!(lines_before_input(",c", (3, 5), 10), Some(0));
+    assert_eq!(lines_before_input("/foo/a", (3, 5), 10), None);
+    assert_eq!(lines_before_input("p", (3, 5), 10), None);
   }
 }
@@ -0,0 +1,278 @@
+// Preview of what a substitution or global command would do, shown below the
+// command while it is being typed
+use std::ops::Range;
+use regex::{Regex, RegexBuilder};
+use add_ed::Ed;
+
+pub struct PreviewLine {
+  // Line number in the buffer
+  pub nr: usize,
+  // The line, as it would be after a substitution
+  pub text: String,
+  // Byte ranges in text of the matches, or of the replacements if substituting
+  pub marks: Vec<Range<usize>>,
+}
+
+pub struct Preview {
+  pub lines: Vec<PreviewLine>,
+  // If there were more affected lines than we were allowed to show
+  pub truncated: bool,
+}
+
+// Get a preview of the given command on the buffer, showing at most max_lines
+// Returns None if the command isn't a substitution or global command, or if
+// it can't be understood yet (such as an invalid regex, halfway typed)
+pub fn preview_command(
+  ed: &Ed,
+  command: &str,
+  max_lines: usize,
+) -> Option<Preview> {
+  let command = command.trim_end_matches('\n');
+  let (index, ch) = super::completion::find_command(command)?;
+  let buffer = ed.history.current();
+  let selection = parse_selection(&command[.. index], ed.selection, buffer.len())?;
+  // The char after the command is the delimiter of its arguments
+  let mut rest = command[index + ch.len_utf8() ..].chars();
+  let delimiter = rest.next()?;
+  let parts = split_delimited(rest.as_str(), delimiter);
+  // Built as add-ed builds it, so ^ and $ match at each line
+  let regex = RegexBuilder::new(&parts[0]).multi_line(true).build().ok()?;
+  let iter = buffer.get_tagged_lines(selection).ok()?
+    .map(|(_, line)| line)
+  ;
+  let mut lines = match ch {
+    's' => preview_substitution(
+      &regex,
+      parts.get(1).map(|s| s.as_str()),
+      parts.get(2).map(|flags| flags.contains('g')).unwrap_or(false),
+      selection.0,
+      iter,
+    ),
+    'g' | 'G' => preview_global(&regex, false, selection.0, iter, max_lines),
+    'v' | 'V' => preview_global(&regex, true, selection.0, iter, max_lines),
+    _ => return None,
+  };
+  // Both find one more line than we show, to know if we truncated
+  let truncated = lines.len() > max_lines;
+  lines.truncate(max_lines);
+  Some(Preview{ lines: lines, truncated: truncated })
+}
+
+// The lines a global command would run on, with their matches marked
+// Invert means the command runs on lines not matching (v and V)
+fn preview_global<'a>(
+  regex: &Regex,
+  invert: bool,
+  first_nr: usize,
+  lines: impl Iterator<Item = &'a str>,
+  max_lines: usize,
+) -> Vec<PreviewLine> {
+  (first_nr ..).zip(lines)
+    .filter_map(|(nr, line)| {
+      let marks: Vec<Range<usize>> = regex.find_iter(line).map(|m| m.range()).collect();
+      if marks.is_empty() != invert { return None; }
+      Some(PreviewLine{ nr: nr, text: line.to_owned(), marks: marks })
+    })
+    .take(max_lines + 1)
+    .collect()
+}
+
+// The lines a substitution would change, as they would be after it
+// Substitutes like add-ed does, on the whole selection joined together. So
+// without the g flag only the first match in the selection is replaced, and
+// replacements may add or join lines. Before the replacement is given we just
+// mark the matches.
+fn preview_substitution<'a>(
+  regex: &Regex,
+  replacement: Option<&str>,
+  global: bool,
+  first_nr: usize,
+  lines: impl Iterator<Item = &'a str>,
+) -> Vec<PreviewLine> {
+  let joined: String = lines.collect();
+  let replacement = replacement.map(substitute_escape);
+  // Replace by hand, so we know where the replacements end up
+  let mut text = String::with_capacity(joined.len());
+  let mut marks = Vec::new();
+  let mut last_end = 0;
+  for captures in regex.captures_iter(&joined).take(if global { usize::MAX } else { 1 }) {
+    // Unwrap is safe, group 0 is always the whole match
+    let whole = captures.get(0).unwrap();
+    text.push_str(&joined[last_end .. whole.start()]);
+    let start = text.len();
+    match &replacement {
+      Some(replacement) => captures.expand(replacement, &mut text),
+      None => text.push_str(whole.as_str()),
+    }
+    marks.push(start .. text.len());
+    last_end = whole.end();
+  }
+  text.push_str(&joined[last_end ..]);
+
+  // Split it back into lines, keeping those with marks in them
+  let mut preview = Vec::new();
+  let mut line_start = 0;
+  for (nr, line) in (first_nr ..).zip(text.split_inclusive('\n')) {
+    let line_end = line_start + line.len();
+    let line_marks: Vec<Range<usize>> = marks.iter()
+      .filter(|m|
+        // Empty matches are marked on the line they are at
+        (m.start < line_end && m.end.max(m.start + 1) > line_start) ||
+        // and lines started by a replaced newline are shown as changed
+        (m.end == line_start && text[(*m).clone()].ends_with('\n'))
+      )
+      .map(|m| m.start.max(line_start) - line_start .. m.end.min(line_end) - line_start)
+      .collect()
+    ;
+    if !line_marks.is_empty() {
+      preview.push(PreviewLine{ nr: nr, text: line.to_owned(), marks: line_marks });
+    }
+    line_start = line_end;
+  }
+  preview
+}
+
+// Interprets escapes in the replacement the way add-ed does
+// (A copy, since add-ed doesn't export its own.)
+fn substitute_escape(input: &str) -> String {
+  let mut out = String::new();
+  let mut escaped = false;
+  for ch in input.chars() {
+    if escaped {
+      match ch {
+        '\\' => out.push('\\'),
+        'n' => out.push('\n'),
+        'r' => out.push('\r'),
+        't' => out.push('\t'),
+        // Other escapes are left as they are
+        c => { out.push('\\'); out.push(c); },
+      }
+      escaped = false;
+    }
+    else if ch == '\\' {
+      escaped = true;
+    }
+    else {
+      out.push(ch);
+    }
+  }
+  out
+}
+
+// Parses the simpler kinds of selection, to find which lines a command targets
+// Returns None for selections we don't understand, since a preview of the
+// wrong lines is worse than none.
+pub fn parse_selection(
+  selection: &str,
+  current: (usize, usize),
+  len: usize,
+) -> Option<(usize, usize)> {
+  // As in add-ed, . means the start of the selection when it is the start or
+  // a lone index, and its end when it is the end
+  let index = |s: &str, default: usize, dot: usize| -> Option<usize> {
+    match s.trim() {
+      "" => Some(default),
+      "." => Some(dot),
+      "$" => Some(len),
+      s => s.parse().ok(),
+    }
+  };
+  let selection = selection.trim();
+  let (start, end) = if selection.is_empty() {
+    current
+  }
+  else if selection == "%" {
+    (1, len)
+  }
+  else if let Some((start, end)) = selection.split_once(',') {
+    (index(start, 1, current.0)?, index(end, len, current.1)?)
+  }
+  else if let Some((start, end)) = selection.split_once(';') {
+    (index(start, current.0, current.0)?, index(end, len, current.1)?)
+  }
+  else {
+    let line = index(selection, current.0, current.0)?;
+    (line, line)
+  };
+  if start == 0 || start > end || end > len { return None; }
+  Some((start, end))
+}
+
+// Splits the text on the unescaped delimiter, unescaping escaped delimiters
+pub fn split_delimited(text: &str, delimiter: char) -> Vec<String> {
+  let mut parts = vec![String::new()];
+  let mut chars = text.chars();
+  while let Some(ch) = chars.next() {
+    if ch == '\\' {
+      match chars.next() {
+        Some(next) if next == delimiter => { parts.last_mut().unwrap().push(next); },
+        Some(next) => { parts.last_mut().unwrap().extend(['\\', next]); },
+        None => { parts.last_mut().unwrap().push('\\'); },
+      }
+    }
+    else if ch == delimiter {
+      parts.push(String::new());
+    }
+    else {
+      parts.last_mut().unwrap().push(ch);
+    }
+  }
+  parts
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn selections() {
+    assert_eq!(parse_selection("", (3, 5), 10), Some((3, 5)));
+    assert_eq!(parse_selection(",", (3, 5), 10), Some((1, 10)));
+    assert_eq!(parse_selection("2,$", (3, 5), 10), Some((2, 10)));
+    assert_eq!(parse_selection(";", (3, 5), 10), Some((3, 10)));
+    assert_eq!(parse_selection("7", (3, 5), 10), Some((7, 7)));
+    assert_eq!(parse_selection(".,$", (3, 5), 10), Some((3, 10)));
+    assert_eq!(parse_selection(",.", (3, 5), 10), Some((1, 5)));
+    assert_eq!(parse_selection(".;.", (3, 5), 10), Some((3, 5)));
+    assert_eq!(parse_selection(".", (3, 5), 10), Some((3, 3)));
+    assert_eq!(parse_selection("4,12", (3, 5), 10), None);
+    assert_eq!(parse_selection("/foo/", (3, 5), 10), None);
+  }
+  #[test]
+  fn delimited_arguments() {
+    assert_eq!(split_delimited("a\\/b/c\\d/g", '/'), vec!["a/b", "c\\d", "g"]);
+    assert_eq!(split_delimited("foo", '/'), vec!["foo"]);
+    assert_eq!(split_delimited("|x|", '|'), vec!["", "x", ""]);
+  }
+  #[test]
+  fn substitution_marks_replacements() {
+    let regex = RegexBuilder::new("o+").multi_line(true).build().unwrap();
+    let lines = ["foo boo\n", "bar\n", "zoo\n"];
+    let preview = preview_substitution(&regex, Some("0"), true, 1, lines.into_iter());
+    assert_eq!(preview.len(), 2);
+    assert_eq!((preview[0].nr, preview[0].text.as_str()), (1, "f0 b0\n"));
+    assert_eq!(preview[0].marks, vec![1 .. 2, 4 .. 5]);
+    assert_eq!((preview[1].nr, preview[1].text.as_str()), (3, "z0\n"));
+  }
+  #[test]
+  fn substitution_without_g_replaces_first_match_in_selection() {
+    let regex = RegexBuilder::new("^b").multi_line(true).build().unwrap();
+    let lines = ["ab\n", "bb\n", "ba\n"];
+    let preview = preview_substitution(&regex, Some("x"), false, 4, lines.into_iter());
+    assert_eq!(preview.len(), 1);
+    assert_eq!((preview[0].nr, preview[0].text.as_str()), (5, "xb\n"));
+    assert_eq!(preview[0].marks, vec![0 .. 1]);
+  }
+  #[test]
+  fn substitution_escapes() {
+    assert_eq!(substitute_escape("a\\nb\\\\c\\td\\&"), "a\nb\\c\td\\&");
+    // A newline in the replacement splits the line
+    let regex = RegexBuilder::new(",").multi_line(true).build().unwrap();
+    let preview = preview_substitution(&regex, Some("\\n"), false, 1, ["a,b\n"].into_iter());
+    assert_eq!(preview.len(), 2);
+    assert_eq!((preview[0].nr, preview[0].text.as_str()), (1, "a\n"));
+    assert_eq!(preview[0].marks, vec![1 .. 2]);
+    assert_eq!((preview[1].nr, preview[1].text.as_str()), (2, "b\n"));
+    assert_eq!(preview[1].marks, vec![0 .. 0]);
+  }
+}
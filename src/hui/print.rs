@@ -9,6 +9,15 @@ use crossterm::{
   }
 };
 use std::io::{Result, Write}; // Needs to be used in for queue and flush
+use std::ops::Range;
+use crate::config::MatchStyle;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use two_face::re_exports::syntect::{
+  easy::HighlightLines,
+  highlighting::HighlightState,
+  parsing::ParseState,
+};
 
 // Create some printing helpers
 fn syntect_to_crossterm_color(
@@ -48,6 +57,7 @@ fn syntect_to_crossterm_color(
 }
 fn apply_style(
   style: two_face::re_exports::syntect::highlighting::Style,
+  mark: Option<MatchStyle>,
   out: &mut impl Write,
 ) -> Result<()> {
   use two_face::re_exports::syntect::highlighting::FontStyle;
@@ -73,14 +83,64 @@ fn apply_style(
   if style.font_style.contains(FontStyle::UNDERLINE) {
     out.queue(SetAttribute(Attribute::Underlined))?;
   }
+  // Marked text gets the match style on top of the syntax styling
+  match mark {
+    Some(MatchStyle::Reverse) => { out.queue(SetAttribute(Attribute::Reverse))?; },
+    Some(MatchStyle::Underline) => { out.queue(SetAttribute(Attribute::Underlined))?; },
+    Some(MatchStyle::Bold) => { out.queue(SetAttribute(Attribute::Bold))?; },
+    None => (),
+  }
   Ok(())
 }
+// Display width of a grapheme cluster when printed at the given column
+// Tabs extend to the next tab stop, other control chars are printed as is and
+// given width 0.
+pub fn grapheme_width(g: &str, column: usize, tab_width: usize) -> usize {
+  match g {
+    "\t" => tab_width - column % tab_width,
+    g => g.width(),
+  }
+}
+// Display width of the text when printed, not including any newline
+pub fn display_width(s: &str, tab_width: usize) -> usize {
+  s.graphemes(true)
+    .filter(|g| !g.ends_with('\n'))
+    .fold(0, |column, g| column + grapheme_width(g, column, tab_width))
+}
+// The longest start of the text that fits within the given display width
+// (For status texts, which shouldn't contain tabs)
+pub fn truncate_to_width(s: &str, width: usize) -> &str {
+  let mut used = 0;
+  for (index, g) in s.grapheme_indices(true) {
+    used += g.width();
+    if used > width { return &s[.. index]; }
+  }
+  s
+}
+// Checks if the given byte index in a line is within any of the marked ranges
+fn is_marked(marks: &[Range<usize>], byte_index: usize) -> bool {
+  marks.iter().any(|r| r.contains(&byte_index))
+}
+// Highlighting errors are returned as IO errors, to not panic in raw mode
+// (They should only come from broken syntax definitions.)
+fn highlight_error(e: two_face::re_exports::syntect::Error) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
 fn reset_style(out: &mut impl Write) -> Result<()> {
   use crossterm::style::{ResetColor, SetAttribute, Attribute};
   out.queue(ResetColor)?; // Not needed for linux, but maybe on windows.
   out.queue(SetAttribute(Attribute::Reset))?;
   Ok(())
 }
+// Prints a marker of hidden text, unstyled
+fn print_marker(
+  marker: char,
+  out: &mut impl Write,
+) -> Result<()> {
+  reset_style(out)?;
+  out.queue(Print(marker))?;
+  Ok(())
+}
 fn print_separator(
   out: &mut impl Write,
   width: usize,
@@ -120,7 +180,7 @@ pub struct PrintData {
 }
 
 // Create a struct to define print settings
-pub struct PrintConf {
+pub struct PrintConf<'a> {
   // Print prefix char at start of every line, before numbering if any
   // Intended to support prefix at command input
   pub prefix: Option<char>,
@@ -128,27 +188,52 @@ pub struct PrintConf {
   // Intended for when printing an actively edited buffer
   pub cursor: Option<(usize, usize)>,
   // Index in iterator from which to print
-  // Intended to feed syntax highlighter with preceding lines without printing them
+  // Preceding lines are only fed to the syntax highlighter, so it understands
+  // multi-line constructs crossing the start of the print
   pub start_line: usize,
+  // Line number of the first printed line, used when numbered
+  pub first_line_nr: usize,
   // If true print line number at start of every line
   pub numbered: bool,
   // If true print like 'ed's literal print mode
   pub literal: bool,
+  // Highlighting state to start from, instead of the syntax' initial state
+  // Intended for giving the state after preceding lines, from HighlightCache
+  pub initial_state: Option<(HighlightState, ParseState)>,
   // If true print a separator before the given text
   pub separator: bool,
+  // If false lines longer than the terminal are cut off instead of wrapped
+  pub wrap: bool,
+  // Line index and nr of columns to scroll it horizontally, when not wrapping
+  // Intended for keeping the cursor visible when editing a long line
+  pub scroll: Option<(usize, usize)>,
+  // Returns byte ranges to mark in the line with given index
+  // Intended for showing search matches
+  pub marker: Option<&'a Marker<'a>>,
 }
 
+// Returns byte ranges to mark in the line with given index and text
+pub type Marker<'a> = dyn Fn(usize, &str) -> Vec<Range<usize>> + 'a;
+
 // Uses state to print the given iterator with given syntax highlighting
 pub fn internal_print(
   state: &HighlightingUI,
   syntax: &two_face::re_exports::syntect::parsing::SyntaxReference,
   text: &mut dyn Iterator<Item = (char, &str)>,
-  conf: PrintConf,
+  conf: PrintConf<'_>,
 ) -> Result<PrintData> {
   let mut stdout = std::io::stdout();
 
   let theme = &state.theme;
-  let mut highlighter = two_face::re_exports::syntect::easy::HighlightLines::new(syntax, theme);
+  let tab_width = state.tab_width_for(syntax);
+  let mut highlighter = match conf.initial_state {
+    Some((highlight_state, parse_state)) => HighlightLines::from_state(
+      theme,
+      highlight_state,
+      parse_state,
+    ),
+    None => HighlightLines::new(syntax, theme),
+  };
 
   // Variables for tracking cursor positions
   // i is used for width to detect when we need to wrap lines over to next line
@@ -168,40 +253,86 @@ pub fn internal_print(
     print_height += 1;
   }
 
-  // Arguably one should give the highlighter all lines before the selection.
-  // Otherwise it fails to understand multiline stuff over the selection edges.
-  // Currently too minor for me to bother, PRs welcome
   for (linenr, line) in text.enumerate() {
+    // Lines before start_line are only highlighted, to get the state right
+    if linenr < conf.start_line {
+      highlighter.highlight_line(line.1, &state.syntax_lib)
+        .map_err(highlight_error)?;
+      continue;
+    }
+    // From here on we count lines from start_line
+    let linenr = linenr - conf.start_line;
 
     // For each new line the byte index starts at 0
     // Used for tracking the offsets of the characters as in a string
     // Needed to understand the cursor which gives byte offsets
     let mut byte_index = 0;
+    // Separate byte index not stopped by the cursor, for marking
+    let mut line_byte_index = 0;
+    // Column in the text of the line, for tab stops
+    // (Unlike i it doesn't include prefix and numbering)
+    let mut column = 0;
+    // When not wrapping, columns scrolled past and if any were marked/cut off
+    let scroll = match conf.scroll {
+      Some((scrolled_line, columns)) if scrolled_line == linenr => columns,
+      _ => 0,
+    };
+    let mut scroll_marked = false;
+    let mut truncated = false;
+    let marks = match conf.marker {
+      Some(marker) => marker(linenr, line.1),
+      None => Vec::new(),
+    };
 
     // Highlight the line first
     let highlighted = highlighter.highlight_line(line.1, &state.syntax_lib)
-      .unwrap(); // TODO: this should be handled, requires change of error type
+      .map_err(highlight_error)?;
     // Iterate over syntactic segments, setting the style for each
     for (style, text) in highlighted {
-      apply_style(style, &mut stdout)?;
-      for ch in text.chars() {
+      let mut marked = is_marked(&marks, line_byte_index);
+      apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
+      for g in text.graphemes(true) {
+
+        // If we have moved into or out of a marked range, update style
+        if is_marked(&marks, line_byte_index) != marked {
+          marked = !marked;
+          apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
+        }
+        line_byte_index += g.len();
 
         // If prefix is given, print at start of real but not wrapped lines
         if let Some(pre) = conf.prefix {
           if i == 0 {
             reset_style(&mut stdout)?;
-            let pre_len = pre.len_utf8();
             stdout.queue(Print(pre))?;
-            i += pre_len;
-            apply_style(style, &mut stdout)?;
+            i += pre.width().unwrap_or(0);
+            apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
           }
         }
 
+        // Width of the grapheme when printed, to wrap before it if it doesn't
+        // fit on the current line (wide chars mustn't be split over lines)
+        let is_newline = g.ends_with('\n');
+        let width = match g {
+          _ if is_newline => if conf.literal { 1 } else { 0 },
+          "$" => if conf.literal { 2 } else { 1 },
+          g => grapheme_width(g, column, tab_width),
+        };
+        if conf.wrap &&
+          i % state.term_size.0 != 0 &&
+          i % state.term_size.0 + width > state.term_size.0
+        {
+          stdout.queue(Print("\n\r"))?;
+          print_height += 1;
+          if passed { y += 1; }
+          i += state.term_size.0 - i % state.term_size.0;
+        }
+
         // If line numbers are active, check if start of line
         if conf.numbered && (i % state.term_size.0 == 0) {
           reset_style(&mut stdout)?;
           // Calculate number and convert to string
-          let tmp_num = (conf.start_line + linenr).to_string();
+          let tmp_num = (conf.first_line_nr + linenr).to_string();
           let tmp_num_len = tmp_num.len(); // Only works because linenr is ascii
           // If this is a new line, print number
           if i == 0 {
@@ -223,12 +354,12 @@ pub fn internal_print(
           stdout.queue(Print('│'))?;
           i += tmp_num_len + 1; // +1 for the separator
           // Finally we MUST restore the styling
-          apply_style(style, &mut stdout)?;
+          apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
         }
 
         // After printing potential prefixes we check against our given cursor, if given
-        // We must check before printing ch, since printing newline resets i
-        // Specifically we check if the cursor is before the current ch
+        // We must check before printing g, since printing newline resets i
+        // Specifically we check if the cursor is before the current g
         if let Some(cur) = conf.cursor {
           if ! passed {
             if (cur.0 == linenr && cur.1 <= byte_index) || cur.0 < linenr {
@@ -239,39 +370,70 @@ pub fn internal_print(
               // Mark that we have passed, this will increment y for each new line started
               passed = true;
             }
-            // For each char while not passed add their len to byte-index
-            // Add after checking, since we otherwise cannot go to char index 0
-            byte_index += ch.len_utf8();
+            // For each grapheme while not passed add their len to byte-index
+            // Add after checking, since we otherwise cannot go to index 0
+            byte_index += g.len();
           }
         }
 
-        // Print the actual character
+        // When not wrapping, graphemes scrolled past or beyond the terminal
+        // width aren't printed, only marked by a '‹' and '›' respectively
+        if !conf.wrap && !is_newline {
+          let hidden = if column < scroll {
+            if !scroll_marked {
+              print_marker('‹', &mut stdout)?;
+              apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
+              i += 1;
+              scroll_marked = true;
+            }
+            true
+          }
+          // Leave the last column for the marker
+          else if truncated || i + width > state.term_size.0.saturating_sub(1) {
+            if !truncated {
+              print_marker('›', &mut stdout)?;
+              apply_style(style, marked.then_some(state.match_style), &mut stdout)?;
+              truncated = true;
+            }
+            true
+          }
+          else { false };
+          if hidden {
+            column += width;
+            continue;
+          }
+        }
+
+        // Print the actual grapheme
         // If literal mode, handle edge cases
-        match ch {
-          '\n' => {
+        let prior_i = i;
+        match g {
+          _ if is_newline => {
             if conf.literal { stdout.queue(Print('$'))?; }
             i = 0;
           },
-          '$' => if conf.literal {
+          "$" => if conf.literal {
             stdout.queue(Print("\\$"))?;
-            i += 2;
           } else {
             stdout.queue(Print('$'))?;
-            i += 1;
           },
-          '\t' => {
-            if conf.literal { stdout.queue(Print("--->"))?; }
-            else { stdout.queue(Print("    "))?; }
-            i += 4;
+          // Tabs are printed as spaces up to the next tab stop
+          "\t" => {
+            if conf.literal { stdout.queue(Print(format!("{:->1$}", '>', width)))?; }
+            else { stdout.queue(Print(" ".repeat(width)))?; }
           },
-          c => {
-            stdout.queue(Print(c))?;
-            i += 1;
+          g => {
+            stdout.queue(Print(g))?;
           },
         }
+        if !is_newline {
+          i += width;
+          column += width;
+        }
 
-        // Check if a new line is needed, aka. newline or wrapping
-        if i % state.term_size.0 == 0 {
+        // Check if a new line is needed, aka. newline or filling the line
+        // (Zero width graphemes don't fill the line, it was already full)
+        if (is_newline || i != prior_i) && i % state.term_size.0 == 0 {
           stdout.queue(Print("\n\r"))?;
           print_height += 1;
           if passed { y += 1; }
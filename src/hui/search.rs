@@ -0,0 +1,62 @@
+// Finding the regex searched for by a command, so its matches can be marked
+// when printing afterwards
+
+// Get the regex searched for by the command, if any
+// That is the pattern of a g or G command, or else the last regex in the
+// selection. Empty patterns reuse the prior regex, so they give None.
+pub fn search_regex(command: &str) -> Option<String> {
+  let command = command.trim_end_matches('\n');
+  let mut selection_regex = None;
+  let mut chars = command.char_indices();
+  while let Some((i, ch)) = chars.next() {
+    match ch {
+      '0' ..= '9' | ',' | ';' | '.' | '$' | '+' | '-' | ' ' => (),
+      '\'' => { chars.next(); },
+      // Regex selections, find the closing delimiter
+      '/' | '?' => {
+        let rest = &command[i + 1 ..];
+        let end = find_unescaped(rest, ch).unwrap_or(rest.len());
+        selection_regex = Some(super::preview::split_delimited(&rest[.. end], ch).remove(0));
+        // Skip past the regex and its closing delimiter
+        let skip = rest[.. end].chars().count() + 1;
+        for _ in 0 .. skip { chars.next(); }
+      },
+      'g' | 'G' => {
+        let mut rest = command[i + 1 ..].chars();
+        let delimiter = rest.next()?;
+        let regex = super::preview::split_delimited(rest.as_str(), delimiter).remove(0);
+        return Some(regex).filter(|r| !r.is_empty());
+      },
+      _ => break,
+    }
+  }
+  selection_regex.filter(|r| !r.is_empty())
+}
+
+// Finds the byte index of the first unescaped delimiter in the text
+fn find_unescaped(text: &str, delimiter: char) -> Option<usize> {
+  let mut escaped = false;
+  for (i, c) in text.char_indices() {
+    if escaped { escaped = false; }
+    else if c == '\\' { escaped = true; }
+    else if c == delimiter { return Some(i); }
+  }
+  None
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn regex_of_command() {
+    assert_eq!(search_regex("/foo/"), Some("foo".to_string()));
+    assert_eq!(search_regex("/fo\\/o/,/bar/p"), Some("bar".to_string()));
+    assert_eq!(search_regex("?baz?n\n"), Some("baz".to_string()));
+    assert_eq!(search_regex(",g/qux/p"), Some("qux".to_string()));
+    assert_eq!(search_regex("/foo/g/bar/d"), Some("bar".to_string()));
+    assert_eq!(search_regex("//p"), None);
+    assert_eq!(search_regex("1,5s/foo/bar/"), None);
+    assert_eq!(search_regex("p"), None);
+  }
+}
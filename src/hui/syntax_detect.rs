@@ -0,0 +1,158 @@
+// Selection of which syntax to highlight a buffer with
+use std::path::Path;
+use two_face::re_exports::syntect::parsing::{
+  SyntaxReference,
+  SyntaxSet,
+};
+
+// Describes where to look for the syntax, in order of priority
+pub struct SyntaxSources<'a> {
+  // Syntax forced for the session, if any
+  pub forced: Option<&'a str>,
+  // Pairs of file glob and syntax name, most specific first
+  pub overrides: &'a [(String, String)],
+  // Path of the file in the buffer
+  pub file: &'a str,
+  // The first and last lines of the buffer, to look for modelines in
+  pub head: &'a [&'a str],
+  pub tail: &'a [&'a str],
+}
+
+// Tries, in order: forced syntax, overrides matching the file, modelines, file
+// name/extension, first line (for shebangs and such). Falls back to plain text.
+pub fn detect_syntax<'a>(
+  syntax_lib: &'a SyntaxSet,
+  sources: SyntaxSources<'_>,
+) -> &'a SyntaxReference {
+  let path = Path::new(sources.file);
+  let file_name = path.file_name()
+    .and_then(|name| name.to_str())
+    .unwrap_or("")
+  ;
+  sources.forced
+    .and_then(|name| syntax_lib.find_syntax_by_token(name))
+    .or_else(|| sources.overrides.iter()
+      .find(|(glob, _)| {
+        // Globs with a path separator match the whole path, others the name
+        if glob.contains(std::path::MAIN_SEPARATOR) {
+          glob_match(glob, sources.file)
+        } else {
+          glob_match(glob, file_name)
+        }
+      })
+      .and_then(|(_, name)| syntax_lib.find_syntax_by_token(name))
+    )
+    .or_else(|| sources.head.iter().chain(sources.tail)
+      .find_map(|line| parse_modeline(line))
+      .and_then(|name| syntax_lib.find_syntax_by_token(name))
+    )
+    // Same as syntect does for files, first by whole name then by extension
+    .or_else(|| syntax_lib.find_syntax_by_extension(file_name))
+    .or_else(|| path.extension()
+      .and_then(|ext| ext.to_str())
+      .and_then(|ext| syntax_lib.find_syntax_by_extension(ext))
+    )
+    .or_else(|| sources.head.first()
+      .and_then(|line| syntax_lib.find_syntax_by_first_line(line))
+    )
+    .unwrap_or_else(|| syntax_lib.find_syntax_plain_text())
+}
+
+// Get the syntax/mode name from a vim or emacs modeline, if the line has one
+fn parse_modeline(line: &str) -> Option<&str> {
+  // Emacs: "-*- mode: rust -*-" or just "-*- rust -*-"
+  if let Some((_, rest)) = line.split_once("-*-") {
+    if let Some((vars, _)) = rest.split_once("-*-") {
+      let vars = vars.trim();
+      if !vars.contains(':') {
+        if !vars.is_empty() { return Some(vars); }
+      }
+      else {
+        for var in vars.split(';') {
+          if let Some((key, value)) = var.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("mode") {
+              return Some(value.trim());
+            }
+          }
+        }
+      }
+    }
+  }
+  // Vim: "vim: set ft=rust :" or "vim: ft=rust" (or with "vi:" or "ex:")
+  for marker in ["vim:", "vi:", "ex:"] {
+    if let Some(start) = line.find(marker) {
+      // The marker must be preceded by whitespace, if not at line start
+      if !line[.. start].is_empty() && !line[.. start].ends_with(char::is_whitespace) {
+        continue;
+      }
+      let options = &line[start + marker.len() ..];
+      for option in options.split(|c: char| c == ':' || c.is_whitespace()) {
+        if let Some((key, value)) = option.split_once('=') {
+          if matches!(key, "ft" | "filetype" | "syn" | "syntax") && !value.is_empty() {
+            return Some(value);
+          }
+        }
+      }
+    }
+  }
+  None
+}
+
+// Simple glob matching, supporting '*' for any nr of chars and '?' for one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let text: Vec<char> = text.chars().collect();
+  let (mut p, mut t) = (0, 0);
+  // Position of the last '*' and where in text we tried matching after it
+  let mut star: Option<(usize, usize)> = None;
+  while t < text.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    }
+    else if p < pattern.len() && pattern[p] == '*' {
+      star = Some((p, t));
+      p += 1;
+    }
+    // On mismatch let the last '*' consume one more char and try again
+    else if let Some((star_p, star_t)) = star {
+      star = Some((star_p, star_t + 1));
+      p = star_p + 1;
+      t = star_t + 1;
+    }
+    else {
+      return false;
+    }
+  }
+  // Trailing '*'s may match nothing
+  pattern[p ..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn glob_matching() {
+    assert!(glob_match("*.rs", "main.rs"));
+    assert!(!glob_match("*.rs", "main.rsx"));
+    assert!(glob_match("Jenkins*", "Jenkinsfile"));
+    assert!(glob_match("COMMIT_EDITMSG", "COMMIT_EDITMSG"));
+    assert!(glob_match("*.y?ml", "config.yaml"));
+    assert!(glob_match("*/.git/*", "repo/.git/COMMIT_EDITMSG"));
+    assert!(!glob_match("a?c", "ac"));
+  }
+  #[test]
+  fn modeline_vim() {
+    assert_eq!(parse_modeline("# vim: set ft=python :"), Some("python"));
+    assert_eq!(parse_modeline("// vim: filetype=rust"), Some("rust"));
+    assert_eq!(parse_modeline("/* vi:syntax=c */"), Some("c"));
+    assert_eq!(parse_modeline("navi: ft=rust"), None);
+  }
+  #[test]
+  fn modeline_emacs() {
+    assert_eq!(parse_modeline("#!/bin/sh -*- mode: sh; tab-width: 2 -*-"), Some("sh"));
+    assert_eq!(parse_modeline(";; -*- lisp -*-"), Some("lisp"));
+    assert_eq!(parse_modeline("just - some -*- text"), None);
+  }
+}
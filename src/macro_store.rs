@@ -1,21 +1,319 @@
+use std::cell::OnceCell;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use add_ed::EdError;
 use add_ed::macros::{
   Macro,
   MacroGetter,
+  NrArguments,
 };
 
 /// Aggregating macro getter
 ///
 /// Tries to get macros, in order, from:
 /// - Configuration
-/// - TODO: Files in specific path
+/// - Macro files, one macro per file named as the macro
+///
+/// Macro files are found when constructed, and each is read once when first
+/// needed. That is when run, or when described by `hints` (which the UI asks
+/// for at startup), `list` or `check_files`.
 pub struct MacroStore<'a> {
   pub config_macros: &'a HashMap<String, Macro>,
+  // Path to each macro file by macro name, with the macro once read
+  file_macros: HashMap<String, (PathBuf, OnceCell<Macro>)>,
+  // Names of macros left out, see skip
+  skipped: Vec<String>,
+}
+
+impl<'a> MacroStore<'a> {
+  /// Create a store of the config macros and the macro files in given dirs
+  ///
+  /// If a macro file with the same name is in several dirs the first is used.
+  /// Dirs that don't exist are ignored.
+  pub fn new(
+    config_macros: &'a HashMap<String, Macro>,
+    macro_dirs: &[PathBuf],
+  ) -> Self {
+    let mut file_macros = HashMap::new();
+    for dir in macro_dirs {
+      let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => continue,
+      };
+      for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = match entry.file_name().into_string() {
+          Ok(name) => name,
+          Err(_) => continue,
+        };
+        // Skip hidden and backup files, such as editor swap files
+        if name.starts_with('.') || name.ends_with('~') || !path.is_file() {
+          continue;
+        }
+        file_macros.entry(name).or_insert((path, OnceCell::new()));
+      }
+    }
+    Self{
+      config_macros: config_macros,
+      file_macros: file_macros,
+      skipped: Vec::new(),
+    }
+  }
+
+  /// Leave out the macros with given names, returning the names left out
+  ///
+  /// Intended for macros that could never be run, such as those named as UI
+  /// commands.
+  pub fn skip(&mut self, names: &[&str]) -> Vec<String> {
+    let skipped: Vec<String> = self.names().into_iter()
+      .filter(|name| names.contains(name))
+      .map(|name| name.to_owned())
+      .collect()
+    ;
+    self.skipped.extend(skipped.iter().cloned());
+    skipped
+  }
+
+  /// Names of all macros, sorted
+  pub fn names(&self) -> Vec<&str> {
+    let mut names: Vec<&str> = self.config_macros.keys()
+      .chain(self.file_macros.keys().filter(|name| !self.config_macros.contains_key(*name)))
+      .filter(|name| !self.skipped.contains(name))
+      .map(|name| name.as_str())
+      .collect()
+    ;
+    names.sort();
+    names
+  }
+
+  /// Path of the file a macro is read from, if it isn't from configuration
+  pub fn file_path(&self, name: &str) -> Option<&Path> {
+    if self.config_macros.contains_key(name) { return None; }
+    self.file_macros.get(name).map(|(path, _)| path.as_path())
+  }
+
+  /// Full definitions of all macros, sorted by name
+  ///
+  /// Reads all macro files, reporting any that fail in place of the macro.
+  pub fn list(&self) -> String {
+    let mut list = String::new();
+    for name in self.names() {
+      match self.get_macro(name) {
+        Ok(Some(m)) => list.push_str(&describe_definition(name, m)),
+        Ok(None) => (),
+        Err(e) => list.push_str(&format!("{}\n  {}\n", name, e)),
+      }
+    }
+    list
+  }
+
+  /// Read all macro files, returning the errors of those that fail to load
+  pub fn check_files(&self) -> Vec<MacroFileError> {
+    let mut paths: Vec<&PathBuf> = self.file_macros.values()
+      .map(|(path, _)| path)
+      .collect()
+    ;
+    paths.sort();
+    paths.into_iter()
+      .filter_map(|path| read_macro_file(path).err())
+      .collect()
+  }
+
+  /// Names and one line descriptions of all macros, sorted by name
+  ///
+  /// Reads all macro files, describing those that fail to load by their path.
+  pub fn hints(&self) -> Vec<(String, String)> {
+    self.names().into_iter()
+      .map(|name| {
+        let description = match self.get_macro(name) {
+          Ok(Some(m)) => describe_macro(m),
+          _ => match self.file_macros.get(name) {
+            Some((path, _)) => format!("from {}", path.display()),
+            None => String::new(),
+          },
+        };
+        (name.to_owned(), description)
+      })
+      .collect()
+  }
 }
 
 impl<'a> MacroGetter for MacroStore<'a> {
   fn get_macro(&self, name: &str) -> Result<Option<&Macro>, EdError> {
-    Ok(self.config_macros.get(name).into())
+    if self.skipped.iter().any(|skipped| skipped == name) {
+      return Ok(None);
+    }
+    if let Some(m) = self.config_macros.get(name) {
+      return Ok(Some(m));
+    }
+    match self.file_macros.get(name) {
+      Some((path, cell)) => {
+        if cell.get().is_none() {
+          let m = read_macro_file(path)
+            .map_err(add_ed::error::UIError::from)
+            .map_err(EdError::UI)
+          ?;
+          // Can't already be set, since we just checked
+          cell.set(m).ok();
+        }
+        Ok(cell.get())
+      },
+      None => Ok(None),
+    }
+  }
+}
+
+/// Error reading or parsing a macro file
+#[derive(Debug)]
+pub struct MacroFileError {
+  pub path: PathBuf,
+  pub error: String,
+}
+impl std::fmt::Display for MacroFileError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "Failed to load macro from '{}': {}", self.path.display(), self.error)
+  }
+}
+impl std::error::Error for MacroFileError{}
+impl add_ed::error::UIErrorTrait for MacroFileError{}
+
+/// Read a macro file
+///
+/// The file contents are the macro input, optionally preceded by a YAML
+/// front-matter between `---` lines giving the other macro fields, such as
+/// `nr_arguments`. Without it the macro takes any nr of arguments.
+pub fn read_macro_file(path: &Path) -> Result<Macro, MacroFileError> {
+  let error = |e: String| MacroFileError{ path: path.to_owned(), error: e };
+  let contents = std::fs::read_to_string(path)
+    .map_err(|e| error(e.to_string()))
+  ?;
+  parse_macro(&contents).map_err(error)
+}
+
+// Parse macro file contents, see read_macro_file
+fn parse_macro(contents: &str) -> Result<Macro, String> {
+  use serde_yaml::{Mapping, Value};
+  let (front_matter, input) = split_front_matter(contents)?;
+  // Build the macro as it would be written in config, to deserialize it the
+  // same way
+  let mut fields = match front_matter {
+    Some(yaml) => match serde_yaml::from_str::<Option<Mapping>>(yaml) {
+      Ok(fields) => fields.unwrap_or_default(),
+      Err(e) => return Err(format!("Invalid front-matter: {}", e)),
+    },
+    None => Mapping::new(),
+  };
+  let nr_arguments = Value::String("nr_arguments".to_string());
+  if !fields.contains_key(&nr_arguments) {
+    fields.insert(nr_arguments, Value::String("any".to_string()));
+  }
+  fields.insert(Value::String("input".to_string()), Value::String(input.to_string()));
+  serde_yaml::from_value(Value::Mapping(fields))
+    .map_err(|e| format!("Invalid front-matter: {}", e))
+}
+
+// Split out the front-matter, if any, from the input
+fn split_front_matter(contents: &str) -> Result<(Option<&str>, &str), String> {
+  // Lines may end with "\r\n", as written on Windows
+  let is_delimiter = |line: &str| line == "---\n" || line == "---\r\n";
+  let mut lines = contents.split_inclusive('\n');
+  let start = match lines.next() {
+    Some(line) if is_delimiter(line) => line.len(),
+    _ => return Ok((None, contents)),
+  };
+  // The front-matter may be empty, so the closing line can come directly
+  let mut end = start;
+  for line in lines {
+    if is_delimiter(line) {
+      return Ok((Some(&contents[start .. end]), &contents[end + line.len() ..]));
+    }
+    end += line.len();
+  }
+  Err("Front-matter is never closed with a '---' line".to_string())
+}
+
+/// Describe how many arguments a macro takes, in words
+pub fn describe_nr_arguments(nr: &NrArguments) -> String {
+  match nr {
+    NrArguments::None => "no arguments".to_string(),
+    NrArguments::Any => "any nr of arguments".to_string(),
+    NrArguments::Exactly(n) => format!("exactly {} argument(s)", n),
+    NrArguments::Between{incl_min, incl_max} => format!("{} to {} arguments", incl_min, incl_max),
+  }
+}
+
+/// Checks if a macro with given nr of arguments accepts the given count
+pub fn accepts_nr_arguments(nr: &NrArguments, count: usize) -> bool {
+  match nr {
+    NrArguments::None => count == 0,
+    NrArguments::Any => true,
+    NrArguments::Exactly(n) => count == *n,
+    NrArguments::Between{incl_min, incl_max} => (*incl_min ..= *incl_max).contains(&count),
+  }
+}
+
+/// Multi line description of a macro, showing its whole definition
+pub fn describe_definition(name: &str, m: &Macro) -> String {
+  let mut description = format!(
+    "{}\n  nr_arguments: {}\n  input:\n",
+    name,
+    describe_nr_arguments(&m.nr_arguments),
+  );
+  for line in m.input.lines() {
+    description.push_str("    ");
+    description.push_str(line);
+    description.push('\n');
+  }
+  description
+}
+
+/// One line description of a macro, for hints
+pub fn describe_macro(m: &Macro) -> String {
+  format!(
+    "{}, runs: {}",
+    describe_nr_arguments(&m.nr_arguments),
+    m.input.trim_end().replace('\n', "\\n"),
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn front_matter_splitting() {
+    assert_eq!(split_front_matter("g/$1/p\n"), Ok((None, "g/$1/p\n")));
+    assert_eq!(
+      split_front_matter("---\nnr_arguments: none\n---\n,p\n"),
+      Ok((Some("nr_arguments: none\n"), ",p\n")),
+    );
+    assert_eq!(split_front_matter("---\n---\n,p\n"), Ok((Some(""), ",p\n")));
+    assert!(split_front_matter("---\nnr_arguments: none\n,p\n").is_err());
+    assert_eq!(
+      split_front_matter("---\r\nnr_arguments: none\r\n---\r\n,p\r\n"),
+      Ok((Some("nr_arguments: none\r\n"), ",p\r\n")),
+    );
+    assert_eq!(split_front_matter("---\r\n---\r\n,p\r\n"), Ok((Some(""), ",p\r\n")));
+  }
+  #[test]
+  fn argument_counts() {
+    assert!(accepts_nr_arguments(&NrArguments::None, 0));
+    assert!(!accepts_nr_arguments(&NrArguments::None, 1));
+    assert!(accepts_nr_arguments(&NrArguments::Any, 7));
+    assert!(!accepts_nr_arguments(&NrArguments::Exactly(1), 2));
+    let between = NrArguments::Between{ incl_min: 2, incl_max: 5 };
+    assert!(accepts_nr_arguments(&between, 5));
+    assert!(!accepts_nr_arguments(&between, 1));
+  }
+  #[test]
+  fn skipped_macros() {
+    let mut config_macros = HashMap::new();
+    config_macros.insert("wrap".to_owned(), Macro::new(",p"));
+    config_macros.insert("grep".to_owned(), Macro::new("g/$1/p"));
+    let mut store = MacroStore::new(&config_macros, &[]);
+    assert_eq!(store.skip(&["syntax", "wrap"]), vec!["wrap".to_owned()]);
+    assert_eq!(store.names(), vec!["grep"]);
+    assert!(matches!(store.get_macro("wrap"), Ok(None)));
+    assert!(matches!(store.get_macro("grep"), Ok(Some(_))));
   }
 }
@@ -1,5 +1,6 @@
 mod config;
 use config::construct_config;
+mod config_check;
 mod macro_store;
 
 // All UI abstractions
@@ -7,6 +8,7 @@ mod hui;
 use hui::error::HighlightingUIError as HUIError;
 
 use add_ed::ui::UI;
+use add_ed::error::EdError;
 
 pub fn main() {
   // Parse CLI arguments, env and config file into a run configuration
@@ -14,13 +16,28 @@ pub fn main() {
   // enabling raw mode)
   let config = construct_config();
   
+  // Create our macro store, with macro files from the project and config dir
+  let macro_dirs = config::macro_dirs(&config.config_dir, config.project_dir.as_deref());
+  let mut macro_store = macro_store::MacroStore::new(&config.macros, &macro_dirs);
+  // Macros the UI commands would hide are left out, with a warning
+  for name in macro_store.skip(hui::UI_COMMANDS) {
+    eprintln!("Warning: {}", HUIError::MacroHidden(name));
+  }
+  // If requested we print the macros and exit
+  if config.list_macros {
+    print!("{}", macro_store.list());
+    std::process::exit(0);
+  }
+
   // Construct editor
-  let mut ui = hui::HighlightingUI::new();
-  let mut io = add_ed::io::LocalIO::new();
-  // Create our macro store
-  let macro_store = macro_store::MacroStore{
-    config_macros: &config.macros,
+  let mut ui = match hui::HighlightingUI::new(&config, macro_store.hints()) {
+    Ok(ui) => ui,
+    Err(e) => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    },
   };
+  let mut io = add_ed::io::LocalIO::new();
   let mut ed = add_ed::Ed::new(&mut io, &macro_store);
   ed.n = config.n;
   ed.l = config.l;
@@ -28,32 +45,58 @@ pub fn main() {
   // Start raw mode before using HighlightingUI
   // Avoid using .unwrap(), .expect() or panic!() when in raw mode, as it leaves
   // the terminal in an unusable state for bash.
-  crossterm::terminal::enable_raw_mode()
-    .map_err(HUIError::RawmodeSwitchFailed)
-    .unwrap()
-  ;
-
-  // Run the editor, saving result
-  let res = (|| -> Result<(), add_ed::error::EdError>{
-    // Handle if hired is started not on column 0 (for example git may do this)
-    // (Doesn't require raw mode to run, but enters and leaves rawmode if not.)
-    let pos = crossterm::cursor::position()
-      .map_err(HUIError::TerminalIOFailed)
-      .unwrap()
+  // Whatever of the setup succeeded is undone in reverse order, however we exit.
+  let res = (|| -> Result<(), EdError> {
+    crossterm::terminal::enable_raw_mode()
+      .map_err(HUIError::RawmodeSwitchFailed)
+      .map_err(ui_error)
+    ?;
+    let res = (|| -> Result<(), EdError> {
+      // Bracketed paste lets us receive pasted text as one event
+      crossterm::execute!(std::io::stdout(), crossterm::event::EnableBracketedPaste)
+        .map_err(HUIError::TerminalIOFailed)
+        .map_err(ui_error)
+      ?;
+      let res = run_editor(&mut ed, &mut ui, &config.path);
+      let disabled = crossterm::execute!(std::io::stdout(), crossterm::event::DisableBracketedPaste)
+        .map_err(HUIError::TerminalIOFailed)
+        .map_err(ui_error)
+      ;
+      res.and(disabled)
+    })();
+    let disabled = crossterm::terminal::disable_raw_mode()
+      .map_err(HUIError::RawmodeSwitchFailed)
+      .map_err(ui_error)
     ;
-    if pos.0 != 0 { print!("\n\r"); }
-
-    let res = ed.run_command(&mut ui, &format!("e{}", config.path));
-    if let Err(e) = res {
-      ui.print_message(&format!("{}", e))?;
-    }
-    ed.run(&mut ui)?;
-    Ok(())
+    res.and(disabled)
   })();
-  // Clear out raw mode before reacting to result
-  crossterm::terminal::disable_raw_mode()
-    .map_err(HUIError::RawmodeSwitchFailed)
-    .unwrap();
   // Panic if we exit because of a fatal error
   res.unwrap();
 }
+
+// Open the file and run the editor until it quits
+// (Requires raw mode.)
+fn run_editor(
+  ed: &mut add_ed::Ed,
+  ui: &mut hui::HighlightingUI,
+  path: &str,
+) -> Result<(), EdError> {
+  // Handle if hired is started not on column 0 (for example git may do this)
+  // (Doesn't require raw mode to run, but enters and leaves rawmode if not.)
+  let pos = crossterm::cursor::position()
+    .map_err(HUIError::TerminalIOFailed)
+    .map_err(ui_error)
+  ?;
+  if pos.0 != 0 { print!("\n\r"); }
+
+  let res = ed.run_command(ui, &format!("e{}", path));
+  if let Err(e) = res {
+    ui.print_message(&format!("{}", e))?;
+  }
+  ed.run(ui)?;
+  Ok(())
+}
+
+fn ui_error(e: HUIError) -> EdError {
+  EdError::UI(e.into())
+}